@@ -49,7 +49,7 @@ impl Debugger {
     fn right(&mut self) { self.pointer = max(0, self.pointer + 1) }
     fn toggle_breakpoint(&mut self) {
         let p = self.pointer;
-        if let Some(i) = self.breakpoints.iter().position(|e| e.clone() == p) {
+        if let Some(i) = self.breakpoints.iter().position(|e| *e == p) {
             self.breakpoints.remove(i);
         } else {
             self.breakpoints.push(self.pointer)
@@ -58,18 +58,29 @@ impl Debugger {
 
     fn draw(&mut self, frame: &mut Frame) {
         let tokens: Vec<String> =
-            self.vm.tokens.iter().map(|e|self.vm.serialize_token(e)).collect();
+            (0..self.vm.tokens.len())
+                .map(|i|self.vm.serialize_token(self.vm.source_token(i)))
+                .collect();
         let layout = layout(frame.area());
         frame.render_widget(source_view(self, &tokens), layout.source);
         let stack_items: Vec<Line> =
-            self.vm.stack.iter().map(|e|Line::from(e.to_string())).collect();
+            self.vm.stack.iter().map(|e|Line::from(self.vm.serialize_token(e))).collect();
         frame.render_widget(
             Paragraph::new(Text::from(stack_items))
                 .block(panel().title(" Stack ")),
             layout.stack,
         );
+        let inspect_items: Vec<Line> = match self.vm.stack.last() {
+            Some(top) => self.vm.inspect(top).into_iter().map(Line::from).collect(),
+            None => Vec::new(),
+        };
+        frame.render_widget(
+            Paragraph::new(Text::from(inspect_items))
+                .block(panel().title(" Inspect ")),
+            layout.inspect,
+        );
         let ctrl_items: Vec<Line> =
-            self.vm.ctrl.iter().map(|e|Line::from(e.to_string())).collect();
+            self.vm.ctrl.iter().map(|e|Line::from(self.vm.serialize_token(e))).collect();
         frame.render_widget(
             Paragraph::new(Text::from(ctrl_items))
                 .block(panel().title(" Control ")),
@@ -9,6 +9,7 @@ pub struct LayoutAreas {
     pub statusbar: Rect,
     pub source: Rect,
     pub stack: Rect,
+    pub inspect: Rect,
     pub ctrl: Rect,
 }
 pub fn layout(area: Rect) -> LayoutAreas {
@@ -27,10 +28,18 @@ pub fn layout(area: Rect) -> LayoutAreas {
             Constraint::Percentage(20),
         ])
         .split(layout[0]);
+    let data = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(vec![
+            Constraint::Percentage(50),
+            Constraint::Percentage(50),
+        ])
+        .split(panes[1]);
     LayoutAreas {
         statusbar: layout[1].offset(Offset::new(1,0)),
         source: panes[0],
-        stack: panes[1],
+        stack: data[0],
+        inspect: data[1],
         ctrl: panes[2],
     }
 }
@@ -53,7 +62,7 @@ pub fn source_view<'a>(app: &Debugger, tokens: &'a [String]) -> Paragraph<'a> {
                 .style(token_style(
                     i+1 == app.vm.index,
                     i == app.pointer as usize,
-                    app.breakpoints.iter().any(|e| e.clone() == i as i32)
+                    app.breakpoints.contains(&(i as i32))
                 ))
         })
         .collect();
@@ -11,26 +11,42 @@ use virtual_machine::VirtualMachine;
 use debugger::Debugger;
 
 fn main() {
-    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let command = match args.first().map(|e| e.as_str()) {
+        Some("compile") | Some("run") => args.remove(0),
+        _ => "run".to_string(),
+    };
+    let output = take_option(&mut args, "-o");
+    let resume = take_option(&mut args, "--resume");
+    let seed = take_option(&mut args, "--seed");
     let flags: Vec<&String> =
-        args.iter().filter(|e| e.chars().next() == Some('-')).collect();
+        args.iter().filter(|e| e.starts_with('-')).collect();
     let files: Vec<&String> = args.iter().skip(flags.len()).collect();
-    let entry_name = files[0].clone();
-    let entry_source = fs::read_to_string(&entry_name).expect("Can't read file");
 
     let debug = has(&flags, "--debug") || has(&flags, "-d");
     let report = has(&flags, "--report") || has(&flags, "-r");
     let time = has(&flags, "--time") || has(&flags, "-t");
+    let threaded = has(&flags, "--threaded");
 
-    let mut vm = VirtualMachine::new();
+    let mut vm = if let Some(image) = resume {
+        VirtualMachine::load_image(&image).expect("Can't load image")
+    } else if files[0].ends_with(".glb") {
+        VirtualMachine::load_program(files[0]).expect("Can't load bytecode")
+    } else {
+        load_sources(files[0].clone(), files)
+    };
     vm.flag_report = report;
-    for filename in files {
-        let name = filename.clone();
-        let source = fs::read_to_string(&filename).expect("Can't read file");
-        vm.register_file(name, source);
+    vm.flag_threaded = threaded;
+    vm.flag_compile = command == "compile";
+    if let Some(seed) = seed {
+        vm.seed_random(seed.parse().expect("--seed expects a whole number"));
     }
-    vm.include(entry_name, entry_source);
-    if debug {
+    if threaded { vm.compile_words() }
+    if command == "compile" {
+        let output = output.expect("compile requires -o <file>");
+        vm.interpret();
+        vm.save_program(&output).expect("Can't write bytecode");
+    } else if debug {
         let mut app = Debugger::new(vm);
         app.run().expect("debugger error");
     } else {
@@ -40,6 +56,24 @@ fn main() {
     }
 }
 
+fn load_sources(entry_name: String, files: Vec<&String>) -> VirtualMachine {
+    let entry_source = fs::read_to_string(&entry_name).expect("Can't read file");
+    let mut vm = VirtualMachine::new();
+    for filename in files {
+        let name = filename.clone();
+        let source = fs::read_to_string(filename).expect("Can't read file");
+        vm.register_file(name, source);
+    }
+    vm.include(entry_name, entry_source);
+    return vm;
+}
+
 fn has(args: &Vec<&String>, needle: &str) -> bool {
-    args.iter().any(|e| e.to_string() == needle.to_string())
+    args.iter().any(|e| *e == needle)
+}
+
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|e| e == name)?;
+    args.remove(i);
+    if i < args.len() { Some(args.remove(i)) } else { None }
 }
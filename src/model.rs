@@ -1,7 +1,7 @@
 use std::{collections::HashMap};
 
 #[derive(Clone, Copy, PartialEq)]
-pub enum Mode { Compile, Def, Var, Quote, Comment }
+pub enum Mode { Compile, Def, Var, Quote, Comment, Locals, Struct }
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum Token {
@@ -14,16 +14,21 @@ pub enum Token {
     Symbol(usize),
     Var(usize),
     Array(usize),
+    Local(usize, usize),
+    Closure(usize),
+    Map(usize),
+    Int(i64),
     Empty,
 }
 impl Token {
+    #[allow(clippy::inherent_to_string, clippy::wrong_self_convention)]
     pub(crate) fn to_string(&self) -> String {
         match self {
             Token::Native(native) => {
                 let nats = create_natives();
                 if let Some(pair) = nats.iter().find(|e| e.1 == native) {
                     let name = pair.0;
-                    return format!("{}", name);
+                    return name.to_string();
                 }
                 panic!("unknown native");
             },
@@ -32,6 +37,8 @@ impl Token {
             Token::Control(Mode::Var) => "Var".to_string(),
             Token::Control(Mode::Quote) => "Quote".to_string(),
             Token::Control(Mode::Comment) => "Comment".to_string(),
+            Token::Control(Mode::Locals) => "Locals".to_string(),
+            Token::Control(Mode::Struct) => "Struct".to_string(),
             Token::Jump(index) => format!("Jump({})", index),
             Token::Number(value) => value.to_string(),
             Token::Bool(value) => value.to_string(),
@@ -39,11 +46,55 @@ impl Token {
             Token::Var(index) => format!("Var({})", index),
             Token::Array(index) => format!("Array({})", index),
             Token::Symbol(index) => format!("Symbol({})", index),
+            Token::Local(slot, _) => format!("Local({})", slot),
+            Token::Closure(index) => format!("Closure({})", index),
+            Token::Map(index) => format!("Map({})", index),
+            Token::Int(value) => value.to_string(),
             Token::Empty => "Empty".to_string(),
         }
     }
 }
 
+/// Entry on the loop stack. `depth` is the control stack height when the
+/// loop started, where the return to the token driving it is pushed. `at`
+/// and `depth` of the single-token loops tell a return from their
+/// quotations apart from a fresh (recursive) entry. `cases` is the height of
+/// the case stack when the loop started.
+#[derive(Clone, Copy, PartialEq)]
+pub enum LoopFrame {
+    Counted { from: f64, to: f64, step: f64, count: usize, body: usize, depth: usize, cases: usize },
+    Infinite { body: usize, depth: usize, cases: usize },
+    While { cond: usize, body: usize, at: usize, depth: usize, cases: usize, testing: bool },
+    Until { body: usize, at: usize, depth: usize, cases: usize },
+}
+impl LoopFrame {
+    pub fn depth(&self) -> usize {
+        return match self {
+            LoopFrame::Counted { depth, .. } | LoopFrame::Infinite { depth, .. } |
+            LoopFrame::While { depth, .. } | LoopFrame::Until { depth, .. } => *depth,
+        };
+    }
+
+    pub fn cases(&self) -> usize {
+        return match self {
+            LoopFrame::Counted { cases, .. } | LoopFrame::Infinite { cases, .. } |
+            LoopFrame::While { cases, .. } | LoopFrame::Until { cases, .. } => *cases,
+        };
+    }
+
+    #[allow(clippy::inherent_to_string, clippy::wrong_self_convention)]
+    pub(crate) fn to_string(&self) -> String {
+        match self {
+            LoopFrame::Counted { from, to, step, .. } => {
+                format!("Counted({}..{} by {})", from, to, step)
+            }
+            LoopFrame::Infinite { .. } => "Infinite".to_string(),
+            LoopFrame::While { .. } => "While".to_string(),
+            LoopFrame::Until { .. } => "Until".to_string(),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Nat {
     Include = 0,
@@ -111,7 +162,106 @@ pub enum Nat {
     Read,
     Write,
     Assert,
-    RevealTokens
+    RevealTokens,
+    SaveImage,
+    Recurse,
+    OpenLocals,
+    CloseLocals,
+    BindLocals,
+    WriteLocal,
+    Catch,
+    Throw,
+    IfElse,
+    Case,
+    Of,
+    EndOf,
+    EndCase,
+    While,
+    Until,
+    Leave,
+    J,
+    K,
+    RangeStep,
+    Times,
+    Each,
+    Map,
+    Filter,
+    Fold,
+    Reduce,
+    Bi,
+    Tri,
+    Keep,
+    Dip,
+    Curry,
+    Compose,
+    Concat,
+    Length,
+    Substring,
+    IndexOf,
+    Split,
+    ToUpper,
+    ToLower,
+    StrEqual,
+    StrLessThan,
+    NumberToString,
+    StringToNumber,
+    Gc,
+    ArrayLength,
+    Resize,
+    Fill,
+    AllotBytes,
+    AllotInts,
+    AllotCells,
+    Struct,
+    EndStruct,
+    NewStruct,
+    MapNew,
+    MapSet,
+    MapGet,
+    MapHas,
+    MapDelete,
+    MapKeys,
+    MapEach,
+    VecNew,
+    Push,
+    Pop,
+    Insert,
+    Remove,
+    Slice,
+    Sort,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Invert,
+    IntToFloat,
+    FloatToInt,
+    Sqrt,
+    Sin,
+    Cos,
+    Tan,
+    Atan2,
+    Exp,
+    Ln,
+    Log10,
+    Min,
+    Max,
+    Clamp,
+    Lerp,
+    Pi,
+    E,
+    IsNan,
+    IsInf,
+    Seed,
+    Random,
+    RandomInt,
+    Shuffle,
+    ComplexAdd,
+    ComplexMul,
+    ComplexSq,
+    ComplexAbs2,
+    Conj,
 }
 
 pub fn create_natives() -> HashMap<&'static str, Nat> {
@@ -182,5 +332,104 @@ pub fn create_natives() -> HashMap<&'static str, Nat> {
         ("!", Nat::Write),
         ("assert", Nat::Assert),
         ("reveal-tokens", Nat::RevealTokens),
+        ("save-image", Nat::SaveImage),
+        ("recurse", Nat::Recurse),
+        ("{:", Nat::OpenLocals),
+        (":}", Nat::CloseLocals),
+        ("bind-locals", Nat::BindLocals),
+        ("->", Nat::WriteLocal),
+        ("catch", Nat::Catch),
+        ("throw", Nat::Throw),
+        ("if-else", Nat::IfElse),
+        ("case", Nat::Case),
+        ("of", Nat::Of),
+        ("endof", Nat::EndOf),
+        ("endcase", Nat::EndCase),
+        ("while", Nat::While),
+        ("until", Nat::Until),
+        ("leave", Nat::Leave),
+        ("j", Nat::J),
+        ("k", Nat::K),
+        ("range-step", Nat::RangeStep),
+        ("times", Nat::Times),
+        ("each", Nat::Each),
+        ("map", Nat::Map),
+        ("filter", Nat::Filter),
+        ("fold", Nat::Fold),
+        ("reduce", Nat::Reduce),
+        ("bi", Nat::Bi),
+        ("tri", Nat::Tri),
+        ("keep", Nat::Keep),
+        ("dip", Nat::Dip),
+        ("curry", Nat::Curry),
+        ("compose", Nat::Compose),
+        ("concat", Nat::Concat),
+        ("length", Nat::Length),
+        ("substring", Nat::Substring),
+        ("index-of", Nat::IndexOf),
+        ("split", Nat::Split),
+        ("to-upper", Nat::ToUpper),
+        ("to-lower", Nat::ToLower),
+        ("str=", Nat::StrEqual),
+        ("str<", Nat::StrLessThan),
+        ("number>string", Nat::NumberToString),
+        ("string>number", Nat::StringToNumber),
+        ("gc", Nat::Gc),
+        ("array-length", Nat::ArrayLength),
+        ("resize", Nat::Resize),
+        ("fill", Nat::Fill),
+        ("allot-bytes", Nat::AllotBytes),
+        ("allot-ints", Nat::AllotInts),
+        ("allot-cells", Nat::AllotCells),
+        ("struct", Nat::Struct),
+        ("end-struct", Nat::EndStruct),
+        ("new-struct", Nat::NewStruct),
+        ("map-new", Nat::MapNew),
+        ("map-set", Nat::MapSet),
+        ("map-get", Nat::MapGet),
+        ("map-has?", Nat::MapHas),
+        ("map-delete", Nat::MapDelete),
+        ("map-keys", Nat::MapKeys),
+        ("map-each", Nat::MapEach),
+        ("vec-new", Nat::VecNew),
+        ("push", Nat::Push),
+        ("pop", Nat::Pop),
+        ("insert", Nat::Insert),
+        ("remove", Nat::Remove),
+        ("slice", Nat::Slice),
+        ("sort", Nat::Sort),
+        ("and", Nat::And),
+        ("or", Nat::Or),
+        ("xor", Nat::Xor),
+        ("shl", Nat::Shl),
+        ("shr", Nat::Shr),
+        ("invert", Nat::Invert),
+        ("int>float", Nat::IntToFloat),
+        ("float>int", Nat::FloatToInt),
+        ("sqrt", Nat::Sqrt),
+        ("sin", Nat::Sin),
+        ("cos", Nat::Cos),
+        ("tan", Nat::Tan),
+        ("atan2", Nat::Atan2),
+        ("exp", Nat::Exp),
+        ("ln", Nat::Ln),
+        ("log10", Nat::Log10),
+        ("min", Nat::Min),
+        ("max", Nat::Max),
+        ("clamp", Nat::Clamp),
+        ("lerp", Nat::Lerp),
+        ("pi", Nat::Pi),
+        ("e", Nat::E),
+        ("nan?", Nat::IsNan),
+        ("inf?", Nat::IsInf),
+        ("seed", Nat::Seed),
+        ("random", Nat::Random),
+        ("random-int", Nat::RandomInt),
+        ("shuffle", Nat::Shuffle),
+        ("c+", Nat::ComplexAdd),
+        ("c*", Nat::ComplexMul),
+        ("csq", Nat::ComplexSq),
+        ("cabs2", Nat::ComplexAbs2),
+        ("conj", Nat::Conj),
     ])
 }
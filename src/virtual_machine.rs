@@ -1,13 +1,32 @@
-use std::cmp::{max, min};
+use std::cmp::min;
 use std::collections::HashMap;
 
 use crate::model;
 use crate::stack::Stack;
-use model::{create_natives, Mode, Nat, Token};
+use model::{create_natives, LoopFrame, Mode, Nat, Token};
 use model::Token::*;
 
+mod arrays;
+mod bytecode;
+mod case;
+mod closures;
+mod combinators;
+mod complex;
+mod dictionary;
 mod evaluate_native;
+mod exceptions;
+mod ints;
+mod gc;
+mod locals;
+mod loops;
+mod maps;
+mod math;
+mod optimize;
 mod pop;
+mod random;
+mod strings;
+mod structs;
+mod threaded;
 
 pub struct DictEntry {
     symbol: usize,
@@ -21,38 +40,100 @@ pub struct Included {
 
 pub struct VirtualMachine {
     pub flag_report: bool,
+    pub flag_threaded: bool,
+    pub flag_compile: bool,
+    precompiled: bool,
+    catching: usize,
     natives: HashMap<&'static str, Nat>,
     includeables: HashMap<String, String>,
     include_stack: Vec<Included>,
     pub index: usize,
     pub tokens: Vec<Token>,
+    originals: HashMap<usize, Token>,
     pub stack: Vec<Token>,
     pub ctrl: Vec<Token>,
-    loops: Vec<Token>,
+    loops: Vec<LoopFrame>,
+    cases: Vec<Token>,
+    case_targets: HashMap<usize, usize>,
     syms: Vec<String>,
+    sym_index: HashMap<String, usize>,
     strs: Vec<String>,
     dict: Vec<DictEntry>,
+    words: HashMap<usize, usize>,
+    word_names: HashMap<usize, usize>,
     vars: Vec<Token>,
-    arrays: Vec<Vec<f64>>,
+    locals: Vec<Token>,
+    frames: Vec<locals::Frame>,
+    local_names: Vec<String>,
+    locals_body: Option<usize>,
+    arrays: Vec<arrays::ArrayData>,
+    free_strs: Vec<usize>,
+    free_arrays: Vec<usize>,
+    allocations: usize,
+    gc_threshold: usize,
+    roots: Vec<Token>,
+    closures: Vec<closures::Closure>,
+    free_closures: Vec<usize>,
+    structs: Vec<structs::StructDef>,
+    struct_names: Vec<String>,
+    records: HashMap<usize, usize>,
+    rng: u64,
+    maps: Vec<maps::MapData>,
+    free_maps: Vec<usize>,
+    code: Vec<threaded::Op>,
+    code_src: Vec<usize>,
+    code_returns: Vec<usize>,
+    compiled: HashMap<usize, usize>,
 }
 
 impl VirtualMachine {
     pub fn new() -> Self {
         Self {
             flag_report: false,
+            flag_threaded: false,
+            flag_compile: false,
+            precompiled: false,
+            catching: 0,
             natives: create_natives(),
             includeables: HashMap::new(),
             include_stack: Vec::new(),
             index: 0,
             tokens: Vec::new(),
+            originals: HashMap::new(),
             stack: Vec::new(),
             ctrl: Vec::new(),
             loops: Vec::new(),
+            cases: Vec::new(),
+            case_targets: HashMap::new(),
             syms: Vec::new(),
+            sym_index: HashMap::new(),
             strs: Vec::new(),
             dict: Vec::new(),
+            words: HashMap::new(),
+            word_names: HashMap::new(),
             vars: Vec::new(),
+            locals: Vec::new(),
+            frames: Vec::new(),
+            local_names: Vec::new(),
+            locals_body: None,
             arrays: Vec::new(),
+            free_strs: Vec::new(),
+            free_arrays: Vec::new(),
+            allocations: 0,
+            gc_threshold: gc::GC_MIN_THRESHOLD,
+            roots: Vec::new(),
+            closures: Vec::new(),
+            free_closures: Vec::new(),
+            structs: Vec::new(),
+            struct_names: Vec::new(),
+            records: HashMap::new(),
+            rng: random::DEFAULT_SEED,
+            maps: Vec::new(),
+            free_maps: Vec::new(),
+            code: Vec::new(),
+            code_src: Vec::new(),
+            code_returns: Vec::new(),
+            compiled: HashMap::new(),
         }
     }
 
@@ -88,36 +169,50 @@ impl VirtualMachine {
         }
     }
 
+    fn has_source(&self) -> bool {
+        return self.include_stack.len() > 0
+            && self.src_pointer() < self.current_source().len();
+    }
+
+    fn parse_next(&mut self) {
+        let token = self.parse_ahead();
+        self.index = self.evaluate(token);
+    }
+
+    /// Parse the next source token and append it without evaluating it.
+    fn parse_ahead(&mut self) -> Token {
+        let src_i = self.src_pointer();
+        let raw = self.current_source()[src_i].clone();
+        let token = self.parse(&raw);
+        self.move_src_pointer();
+        self.tokens.push(token);
+        return token;
+    }
+
     pub fn debug_step(&mut self) -> bool {
-        if self.include_stack.len() > 0 && self.src_pointer() < self.current_source().len() {
-            if self.index < self.tokens.len() {
-                let token = self.tokens[self.index];
-                self.index = self.evaluate(token);
-            } else {
-                let src_i = self.src_pointer();
-                let raw = self.current_source()[src_i].clone();
-                let token = self.parse(&raw);
-                self.move_src_pointer();
-                self.tokens.push(token);
-                self.index = self.evaluate(token);
-            }
+        self.maybe_collect();
+        if self.index < self.tokens.len() {
+            let token = self.tokens[self.index];
+            self.index = self.evaluate(token);
+            return true;
+        }
+        if self.has_source() {
+            self.parse_next();
             return true;
         }
         return false;
     }
 
     pub fn interpret(&mut self) {
-        while self.include_stack.len() > 0 && self.src_pointer() < self.current_source().len() {
+        loop {
             while self.index < self.tokens.len() {
                 let token = self.tokens[self.index];
                 self.index = self.evaluate(token);
+                self.maybe_collect();
             }
-            let src_i = self.src_pointer();
-            let raw = self.current_source()[src_i].clone();
-            let token = self.parse(&raw);
-            self.move_src_pointer();
-            self.tokens.push(token);
-            self.index = self.evaluate(token);
+            if !self.has_source() { return }
+            self.parse_next();
+            self.maybe_collect();
         }
     }
 
@@ -130,49 +225,87 @@ impl VirtualMachine {
         }
         if let Some(Control(Mode::Def)) = self.ctrl.last() {
             self.ctrl.pop();
-            if self.syms.iter().any(|e| *e == raw_token) {
-                self.panic("Symbol already defined");
-            }
-            self.syms.push(raw_token.to_string());
-            return Symbol(self.syms.len() - 1);
+            return Symbol(self.add_symbol(raw_token));
         }
         if let Some(Control(Mode::Var)) = self.ctrl.last() {
             self.ctrl.pop();
-            if self.syms.iter().any(|e| *e == raw_token) {
-                self.print_trace();
-                panic!("Symbol {} already defined", raw_token);
-            }
+            let symbol = self.add_symbol(raw_token);
             self.vars.push(Number(0.));
-            self.syms.push(raw_token.to_string());
             self.tokens.push(Empty);
-            self.tokens.push(Symbol(self.syms.len() - 1));
+            self.tokens.push(Symbol(symbol));
             self.tokens.push(Native(Nat::OpenBrace));
             self.tokens.push(Var(self.vars.len() - 1));
             self.tokens.push(Native(Nat::CloseBrace));
             self.tokens.push(Native(Nat::Semicolon));
             return Empty;
         }
+        if let Some(Control(Mode::Locals)) = self.ctrl.last() {
+            if let Some(Nat::CloseLocals) = self.natives.get(raw_token) {
+                self.ctrl.pop();
+                self.tokens.push(Empty);
+                self.tokens.push(Number(self.locals_body.unwrap() as f64));
+                self.tokens.push(Number(self.local_names.len() as f64));
+                self.tokens.push(Native(Nat::BindLocals));
+            } else {
+                self.local_names.push(raw_token.to_string());
+            }
+            return Empty
+        }
+        if let Some(Control(Mode::Struct)) = self.ctrl.last() {
+            if let Some(Nat::EndStruct) = self.natives.get(raw_token) {
+                self.ctrl.pop();
+                self.define_struct();
+            } else {
+                self.struct_names.push(raw_token.to_string());
+            }
+            return Empty
+        }
+        // the body being compiled
+        if let Some(Nat::Recurse) = self.natives.get(raw_token) {
+            let Some(start) = self.compiling_body() else {
+                self.panic("recurse outside of a definition")
+            };
+            return Jump(start);
+        }
+        if let Some(Nat::OpenLocals) = self.natives.get(raw_token) {
+            let Some(start) = self.compiling_body() else {
+                self.panic("locals outside of a definition")
+            };
+            if self.locals_body == Some(start) {
+                self.panic("locals already declared in this definition");
+            }
+            self.locals_body = Some(start);
+            self.local_names.clear();
+            self.ctrl.push(Control(Mode::Locals));
+            return Empty
+        }
+        // local of the body being compiled
+        if let Some(body) = self.locals_body.filter(|e| Some(*e) == self.compiling_body()) {
+            if let Some(slot) = self.local_names.iter().position(|e| e == raw_token) {
+                return Local(slot, body);
+            }
+        }
         // native
         if let Some(native) = self.natives.get(raw_token) {
             return Native(*native)
         }
         // number
-        if let Some(number) = raw_token.parse::<f64>().ok() {
+        if let Ok(number) = raw_token.parse::<f64>() {
             return Number(number);
         }
+        if let Some(int) = ints::parse_int(raw_token) {
+            return Int(int);
+        }
         // string
         if raw_token.starts_with("\"") && raw_token.ends_with("\"") {
             let s = raw_token[1..raw_token.len()-1]
                 .to_string()
                 .replace("\\n", "\n");
-            self.strs.push(s);
-            return Str(self.strs.len() - 1);
+            return self.alloc_str(s);
         }
         // word in dict
-        if let Some(symb_i) = self.syms.iter().position(|w| w == raw_token) {
-            if let Some(entry) = self.dict.iter().find(|e| e.symbol == symb_i) {
-                return Jump(entry.jump)
-            }
+        if let Some(jump) = self.lookup_word(raw_token) {
+            return Jump(jump)
         }
         self.print_trace();
         panic!("unknown word '{}'", raw_token)
@@ -203,47 +336,80 @@ impl VirtualMachine {
             Array(x) => self.stack.push(Array(x)),
             Control(_) => self.panic("cannot evaluate a control token"),
             Jump(index) => {
-                self.ctrl.push(Jump(self.index + 1));
-                return index;
+                if let Some(entry) = self.compiled.get(&index).filter(|_| self.flag_threaded) {
+                    let ret = self.index + 1;
+                    self.run_compiled(*entry);
+                    return ret;
+                }
+                return self.call(index);
             }
             Bool(x) => self.stack.push(Bool(x)),
             Empty => {},
             Symbol(index) => self.stack.push(Symbol(index)),
+            Closure(index) => self.stack.push(Closure(index)),
+            Map(index) => self.stack.push(Map(index)),
+            Int(value) => self.stack.push(Int(value)),
+            Local(slot, body) => {
+                let token = self.read_local(slot, body);
+                self.stack.push(token);
+            }
             Str(index) => self.stack.push(Str(index)),
         }
         return self.index + 1;
     }
 
+    fn compiling_body(&self) -> Option<usize> {
+        if let (Some(Control(Mode::Compile)), Some(Jump(start))) =
+            (self.ctrl.last(), self.stack.last()) {
+            return Some(*start);
+        }
+        return None;
+    }
+
+    /// Push the return address and continue at `target`. In tail position,
+    /// right before a closing brace, the callee returns for us instead,
+    /// unless this call still has locals to release.
+    pub fn call(&mut self, target: usize) -> usize {
+        let next = self.tokens[self.index + 1..].iter().find(|e| **e != Empty);
+        if next != Some(&Native(Nat::CloseBrace)) || self.owns_locals() {
+            self.ctrl.push(Jump(self.index + 1));
+        }
+        return target;
+    }
+
     pub fn serialize_token(&self, token: &Token) -> String {
         return match token {
             Jump(jmp) => {
-                if let Some(word) = self.dict.iter().find(|e| e.jump == *jmp) {
-                    self.syms[word.symbol].clone()
+                if let Some(name) = self.word_name(*jmp) {
+                    name.clone()
                 } else {
                     format!("Jump({})", jmp)
                 }
             },
-            Symbol(i) => self.syms[i.clone()].clone(),
-            Str(i) => format!("\"{}\"", self.strs[i.clone()]),
+            Symbol(i) => self.syms[*i].clone(),
+            Str(i) => format!("\"{}\"", self.strs[*i]),
+            Closure(i) => self.serialize_closure(*i),
             Empty => "".to_string(),
             _ => token.to_string().clone(),
         }
     }
 
     pub fn print_trace(&self) {
-        let from = max(0, self.index-15);
-        let to = min(self.index-15, self.tokens.len()-1);
+        let from = self.index.saturating_sub(15);
+        let to = min(self.index, self.tokens.len().saturating_sub(1));
         let context = self.tokens[from..=to].iter();
         let strings: Vec<String> = context.map(|x| self.serialize_token(x)).collect();
         println!("\x1b[93m{}\x1b[0m", strings.join(" "));
     }
 
     pub fn panic(&self, msg: &'static str) -> ! {
+        self.raise(msg);
         if self.flag_report {
             self.print_trace();
             print!("data stack: "); self.stack.print();
             print!("ctrl stack: "); self.ctrl.print();
-            print!("loop stack: "); self.loops.print();
+            let loops: Vec<String> = self.loops.iter().map(|e| e.to_string()).collect();
+            println!("loop stack: <{}> {}", loops.len(), loops.join(" "));
             println!("token pointer {}", self.index);
             panic!("{}", msg);
         }
@@ -0,0 +1,256 @@
+use crate::model::{Token, Token::*};
+use super::maps::Key;
+use super::VirtualMachine;
+
+/// Storage behind an `Array` token. Numeric arrays take floats and ints and
+/// convert on `set`: bytes saturate to 0..=255 and ints drop the fraction.
+/// Int arrays read back ints, the others floats. Cells hold any token.
+pub enum ArrayData {
+    Floats(Vec<f64>),
+    Bytes(Vec<u8>),
+    Ints(Vec<i64>),
+    Cells(Vec<Token>),
+}
+
+impl ArrayData {
+    pub fn len(&self) -> usize {
+        return match self {
+            ArrayData::Floats(v) => v.len(),
+            ArrayData::Bytes(v) => v.len(),
+            ArrayData::Ints(v) => v.len(),
+            ArrayData::Cells(v) => v.len(),
+        };
+    }
+
+    pub fn is_cells(&self) -> bool {
+        return matches!(self, ArrayData::Cells(_));
+    }
+
+    pub fn get(&self, index: usize) -> Token {
+        return match self {
+            ArrayData::Floats(v) => Number(v[index]),
+            ArrayData::Bytes(v) => Number(v[index] as f64),
+            ArrayData::Ints(v) => Int(v[index]),
+            ArrayData::Cells(v) => v[index],
+        };
+    }
+
+    /// Store `value`, which must be a number unless this is a cell array.
+    pub fn set(&mut self, index: usize, value: Token) {
+        match (self, value) {
+            (ArrayData::Cells(v), value) => v[index] = value,
+            (ArrayData::Floats(v), Number(x)) => v[index] = x,
+            (ArrayData::Bytes(v), Number(x)) => v[index] = x as u8,
+            (ArrayData::Ints(v), Number(x)) => v[index] = x as i64,
+            (ArrayData::Floats(v), Int(x)) => v[index] = x as f64,
+            (ArrayData::Bytes(v), Int(x)) => v[index] = x.clamp(0, 255) as u8,
+            (ArrayData::Ints(v), Int(x)) => v[index] = x,
+            _ => panic!("numeric arrays only hold numbers"),
+        }
+    }
+
+    /// An empty array of the same element type.
+    pub fn empty_like(&self) -> ArrayData {
+        return match self {
+            ArrayData::Floats(_) => ArrayData::Floats(Vec::new()),
+            ArrayData::Bytes(_) => ArrayData::Bytes(Vec::new()),
+            ArrayData::Ints(_) => ArrayData::Ints(Vec::new()),
+            ArrayData::Cells(_) => ArrayData::Cells(Vec::new()),
+        };
+    }
+
+    /// Grow with zeros or truncate to `len`.
+    pub fn resize(&mut self, len: usize) {
+        match self {
+            ArrayData::Floats(v) => v.resize(len, 0.),
+            ArrayData::Bytes(v) => v.resize(len, 0),
+            ArrayData::Ints(v) => v.resize(len, 0),
+            ArrayData::Cells(v) => v.resize(len, Number(0.)),
+        }
+    }
+
+    pub fn push(&mut self, value: Token) {
+        let len = self.len();
+        self.resize(len + 1);
+        self.set(len, value);
+    }
+
+    pub fn insert(&mut self, index: usize, value: Token) {
+        self.push(value);
+        match self {
+            ArrayData::Floats(v) => v[index..].rotate_right(1),
+            ArrayData::Bytes(v) => v[index..].rotate_right(1),
+            ArrayData::Ints(v) => v[index..].rotate_right(1),
+            ArrayData::Cells(v) => v[index..].rotate_right(1),
+        }
+    }
+
+    pub fn remove(&mut self, index: usize) -> Token {
+        let value = self.get(index);
+        match self {
+            ArrayData::Floats(v) => { v.remove(index); }
+            ArrayData::Bytes(v) => { v.remove(index); }
+            ArrayData::Ints(v) => { v.remove(index); }
+            ArrayData::Cells(v) => { v.remove(index); }
+        }
+        return value;
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) {
+        match self {
+            ArrayData::Floats(v) => v.swap(a, b),
+            ArrayData::Bytes(v) => v.swap(a, b),
+            ArrayData::Ints(v) => v.swap(a, b),
+            ArrayData::Cells(v) => v.swap(a, b),
+        }
+    }
+
+    pub fn slice(&self, start: usize, end: usize) -> ArrayData {
+        return match self {
+            ArrayData::Floats(v) => ArrayData::Floats(v[start..end].to_vec()),
+            ArrayData::Bytes(v) => ArrayData::Bytes(v[start..end].to_vec()),
+            ArrayData::Ints(v) => ArrayData::Ints(v[start..end].to_vec()),
+            ArrayData::Cells(v) => ArrayData::Cells(v[start..end].to_vec()),
+        };
+    }
+}
+
+impl VirtualMachine {
+    /// Check that `index` is a whole number inside the array, reporting the
+    /// word, the index and the array length otherwise.
+    pub fn array_index(&self, array_ref: usize, index: f64) -> usize {
+        return self.index_below(array_ref, index, self.arrays[array_ref].len());
+    }
+
+    /// Like `array_index`, but also accepting positions up to `limit`, for
+    /// words that address the gaps between elements.
+    fn index_below(&self, array_ref: usize, index: f64, limit: usize) -> usize {
+        let len = self.arrays[array_ref].len();
+        if index >= 0. && index.fract() == 0. && (index as usize) < limit {
+            return index as usize;
+        }
+        self.raise("array index out of bounds");
+        let word = self.serialize_token(&self.tokens[self.index]);
+        println!("\x1b[91m'{}' index {} out of bounds for array of length {}\x1b[0m",
+            word, index, len);
+        self.panic("array index out of bounds");
+    }
+
+    /// Pop a value that fits arrays like `array_ref`: anything for cells,
+    /// a float or int otherwise.
+    pub fn pop_element(&mut self, array_ref: usize) -> Token {
+        if self.arrays[array_ref].is_cells() { return self.pop_token() }
+        if let Some(Int(_)) = self.stack.last() { return Int(self.pop_int()) }
+        return Number(self.pop_num());
+    }
+
+    /// Allocate `len` zeroed elements of the kind made by `make`.
+    pub fn allot(&mut self, make: fn(usize) -> ArrayData) {
+        let len = self.pop_index() as usize;
+        let array = self.alloc_array(make(len));
+        self.stack.push(array);
+    }
+
+    pub fn resize(&mut self) {
+        let array_ref = self.pop_array();
+        let len = self.pop_index();
+        if len < 0. || len.fract() != 0. {
+            self.stop("expected a whole length", Some(Number(len)));
+        }
+        self.arrays[array_ref].resize(len as usize);
+    }
+
+    pub fn push_element(&mut self) {
+        let array_ref = self.pop_array();
+        let value = self.pop_element(array_ref);
+        self.arrays[array_ref].push(value);
+    }
+
+    pub fn pop_last(&mut self) {
+        let array_ref = self.pop_array();
+        let len = self.arrays[array_ref].len();
+        if len == 0 { self.panic("pop from an empty array") }
+        let value = self.arrays[array_ref].remove(len - 1);
+        self.stack.push(value);
+    }
+
+    pub fn insert(&mut self) {
+        let array_ref = self.pop_array();
+        let index = self.pop_index();
+        let value = self.pop_element(array_ref);
+        let len = self.arrays[array_ref].len();
+        let index = self.index_below(array_ref, index, len + 1);
+        self.arrays[array_ref].insert(index, value);
+    }
+
+    pub fn remove(&mut self) {
+        let array_ref = self.pop_array();
+        let index = self.pop_index();
+        let index = self.array_index(array_ref, index);
+        let value = self.arrays[array_ref].remove(index);
+        self.stack.push(value);
+    }
+
+    /// New array with the elements from `start` up to, not including, `end`.
+    pub fn slice(&mut self) {
+        let array_ref = self.pop_array();
+        let end = self.pop_index();
+        let start = self.pop_index();
+        let len = self.arrays[array_ref].len();
+        let end = self.index_below(array_ref, end, len + 1);
+        let start = self.index_below(array_ref, start, end + 1);
+        let array = self.alloc_array(self.arrays[array_ref].slice(start, end));
+        self.stack.push(array);
+    }
+
+    /// New array with the elements of `left` followed by those of `right`,
+    /// of the same type as `left`.
+    pub fn concat_arrays(&mut self, left: usize, right: usize) {
+        let mut joined = self.arrays[left].slice(0, self.arrays[left].len());
+        for i in 0..self.arrays[right].len() {
+            let value = self.arrays[right].get(i);
+            if !joined.is_cells() && !matches!(value, Number(_) | Int(_)) {
+                self.stop("expected a number", Some(value));
+            }
+            joined.push(value);
+        }
+        let array = self.alloc_array(joined);
+        self.stack.push(array);
+    }
+
+    /// Sort in place. Cell arrays may only hold numbers and strings, which
+    /// sort like map keys.
+    pub fn sort(&mut self) {
+        let array_ref = self.pop_array();
+        if let ArrayData::Cells(cells) = &self.arrays[array_ref] {
+            let mut keyed = Vec::with_capacity(cells.len());
+            for token in cells.clone() {
+                let key = match token {
+                    Number(x) => Key::Num(x),
+                    Int(x) => Key::Int(x),
+                    Str(i) => Key::Str(self.strs[i].clone()),
+                    token => self.stop("sort expected numbers or strings", Some(token)),
+                };
+                keyed.push((key, token));
+            }
+            keyed.sort_by(|a, b| a.0.cmp(&b.0));
+            let sorted = keyed.into_iter().map(|e| e.1).collect();
+            self.arrays[array_ref] = ArrayData::Cells(sorted);
+            return;
+        }
+        match &mut self.arrays[array_ref] {
+            ArrayData::Floats(v) => v.sort_by(|a, b| a.total_cmp(b)),
+            ArrayData::Bytes(v) => v.sort(),
+            ArrayData::Ints(v) => v.sort(),
+            ArrayData::Cells(_) => {}
+        }
+    }
+
+    pub fn fill(&mut self) {
+        let array_ref = self.pop_array();
+        let value = self.pop_element(array_ref);
+        for i in 0..self.arrays[array_ref].len() {
+            self.arrays[array_ref].set(i, value);
+        }
+    }
+}
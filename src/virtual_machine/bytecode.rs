@@ -0,0 +1,549 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+use crate::model::{create_natives, LoopFrame, Mode, Nat, Token, Token::*};
+use super::{Included, VirtualMachine};
+use super::arrays::ArrayData;
+use super::closures::Closure;
+use super::locals::Frame;
+use super::maps::{Key, MapData};
+use super::structs::StructDef;
+
+const PROGRAM_MAGIC: &[u8; 4] = b"GLRB";
+const IMAGE_MAGIC: &[u8; 4] = b"GLRI";
+const VERSION: u16 = 15;
+
+impl VirtualMachine {
+    /// Write the compiled program to `path`. Only the code is kept: the
+    /// stacks are dropped, variables get the value a fresh `var` starts with
+    /// and whatever the run allocated outside the token stream is collected.
+    pub fn save_program(&mut self, path: &str) -> Result<()> {
+        self.stack.clear();
+        self.ctrl.clear();
+        self.cases.clear();
+        self.loops.clear();
+        self.locals.clear();
+        self.frames.clear();
+        self.vars.fill(Number(0.));
+        self.collect();
+        let mut out = Writer::new(PROGRAM_MAGIC);
+        out.usize(0);
+        self.write_tables(&mut out);
+        return fs::write(path, out.bytes);
+    }
+
+    /// Load a program written by `save_program`, ready to execute from its
+    /// entry point without any source to parse.
+    pub fn load_program(path: &str) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        let mut input = Reader::new(&bytes, PROGRAM_MAGIC)?;
+        let mut vm = VirtualMachine::new();
+        vm.precompiled = true;
+        vm.index = input.usize()?;
+        vm.read_tables(&mut input)?;
+        return Ok(vm);
+    }
+
+    /// Write the complete machine state to `path`, resuming at `resume_at`.
+    pub fn save_image(&self, path: &str, resume_at: usize) -> Result<()> {
+        let mut out = Writer::new(IMAGE_MAGIC);
+        out.usize(resume_at);
+        self.write_tables(&mut out);
+        out.bytes.push(self.precompiled as u8);
+        out.usize(self.rng as usize);
+        for stack in [&self.stack, &self.ctrl, &self.cases] {
+            out.usize(stack.len());
+            for token in stack { out.token(token) }
+        }
+        out.usize(self.loops.len());
+        for frame in &self.loops { out.loop_frame(frame) }
+        out.usize(self.locals.len());
+        for token in &self.locals { out.token(token) }
+        out.usize(self.frames.len());
+        for frame in &self.frames {
+            out.usize(frame.base);
+            out.usize(frame.depth);
+            out.usize(frame.body);
+        }
+        out.usize(self.include_stack.len());
+        for included in &self.include_stack {
+            out.usize(included.source_index);
+            out.usize(included.tokens.len());
+            for raw in &included.tokens { out.string(raw) }
+        }
+        let mut includeables: Vec<_> = self.includeables.iter().collect();
+        includeables.sort();
+        out.usize(includeables.len());
+        for (name, content) in includeables {
+            out.string(name);
+            out.string(content);
+        }
+        return fs::write(path, out.bytes);
+    }
+
+    /// Load an image written by `save_image`, continuing where it was saved.
+    pub fn load_image(path: &str) -> Result<Self> {
+        let bytes = fs::read(path)?;
+        let mut input = Reader::new(&bytes, IMAGE_MAGIC)?;
+        let mut vm = VirtualMachine::new();
+        vm.index = input.usize()?;
+        vm.read_tables(&mut input)?;
+        vm.precompiled = input.byte()? != 0;
+        vm.rng = input.usize()? as u64;
+        for stack in [&mut vm.stack, &mut vm.ctrl, &mut vm.cases] {
+            for _ in 0..input.usize()? { stack.push(input.token()?) }
+        }
+        for _ in 0..input.usize()? { vm.loops.push(input.loop_frame()?) }
+        for _ in 0..input.usize()? { vm.locals.push(input.token()?) }
+        for _ in 0..input.usize()? {
+            let base = input.usize()?;
+            let depth = input.usize()?;
+            let body = input.usize()?;
+            vm.frames.push(Frame { base, depth, body });
+        }
+        for _ in 0..input.usize()? {
+            let source_index = input.usize()?;
+            let mut tokens = Vec::new();
+            for _ in 0..input.usize()? { tokens.push(input.string()?) }
+            vm.include_stack.push(Included { source_index, tokens });
+        }
+        for _ in 0..input.usize()? {
+            let name = input.string()?;
+            let content = input.string()?;
+            vm.register_file(name, content);
+        }
+        return Ok(vm);
+    }
+
+    /// Hash maps are written sorted by key so the output is reproducible.
+    fn write_tables(&self, out: &mut Writer) {
+        out.usize(self.tokens.len());
+        for token in &self.tokens { out.token(token) }
+        let mut originals: Vec<_> = self.originals.iter().collect();
+        originals.sort_by_key(|e| e.0);
+        out.usize(originals.len());
+        for (index, token) in originals {
+            out.usize(*index);
+            out.token(token);
+        }
+        out.usize(self.syms.len());
+        for sym in &self.syms { out.string(sym) }
+        out.usize(self.strs.len());
+        for str in &self.strs { out.string(str) }
+        out.usize(self.dict.len());
+        for entry in &self.dict {
+            out.usize(entry.symbol);
+            out.usize(entry.jump);
+        }
+        out.usize(self.vars.len());
+        for var in &self.vars { out.token(var) }
+        out.usize(self.arrays.len());
+        for array in &self.arrays {
+            out.array(array);
+        }
+        out.usize(self.closures.len());
+        for closure in &self.closures {
+            let (tag, a, b) = match closure {
+                Closure::Curry(a, b) => (0, a, b),
+                Closure::Compose(a, b) => (1, a, b),
+            };
+            out.bytes.push(tag);
+            out.token(a);
+            out.token(b);
+        }
+        out.usize(self.structs.len());
+        for def in &self.structs {
+            out.string(&def.name);
+            out.usize(def.fields.len());
+            for field in &def.fields { out.string(field) }
+        }
+        let mut records: Vec<_> = self.records.iter().collect();
+        records.sort();
+        out.usize(records.len());
+        for (array_ref, id) in records {
+            out.usize(*array_ref);
+            out.usize(*id);
+        }
+        out.usize(self.maps.len());
+        for map in &self.maps {
+            out.usize(map.len());
+            for (key, value) in map {
+                match key {
+                    Key::Num(x) => { out.bytes.push(0); out.f64(*x) }
+                    Key::Str(s) => { out.bytes.push(1); out.string(s) }
+                    Key::Int(x) => { out.bytes.push(2); out.usize(*x as usize) }
+                }
+                out.token(value);
+            }
+        }
+    }
+
+    fn read_tables(&mut self, input: &mut Reader) -> Result<()> {
+        for _ in 0..input.usize()? { self.tokens.push(input.token()?) }
+        for _ in 0..input.usize()? {
+            let index = input.usize()?;
+            let token = input.token()?;
+            self.originals.insert(index, token);
+        }
+        for _ in 0..input.usize()? {
+            let sym = input.string()?;
+            self.add_symbol(&sym);
+        }
+        for _ in 0..input.usize()? { self.strs.push(input.string()?) }
+        for _ in 0..input.usize()? {
+            let symbol = input.usize()?;
+            let jump = input.usize()?;
+            self.define(symbol, jump);
+        }
+        for _ in 0..input.usize()? { self.vars.push(input.token()?) }
+        for _ in 0..input.usize()? { self.arrays.push(input.array()?) }
+        for _ in 0..input.usize()? {
+            let tag = input.byte()?;
+            let (a, b) = (input.token()?, input.token()?);
+            self.closures.push(match tag {
+                0 => Closure::Curry(a, b),
+                1 => Closure::Compose(a, b),
+                _ => return Err(invalid("unknown closure")),
+            });
+        }
+        for _ in 0..input.usize()? {
+            let name = input.string()?;
+            let fields = (0..input.usize()?).map(|_| input.string()).collect::<Result<_>>()?;
+            self.structs.push(StructDef { name, fields });
+        }
+        for _ in 0..input.usize()? {
+            let array_ref = input.usize()?;
+            let id = input.usize()?;
+            self.records.insert(array_ref, id);
+        }
+        for _ in 0..input.usize()? {
+            let mut map = MapData::new();
+            for _ in 0..input.usize()? {
+                let key = match input.byte()? {
+                    0 => Key::Num(input.f64()?),
+                    1 => Key::Str(input.string()?),
+                    2 => Key::Int(input.usize()? as i64),
+                    _ => return Err(invalid("unknown map key")),
+                };
+                map.insert(key, input.token()?);
+            }
+            self.maps.push(map);
+        }
+        return Ok(());
+    }
+}
+
+fn invalid(msg: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, msg);
+}
+
+fn natives_by_code() -> Vec<Option<Nat>> {
+    let mut natives = vec![None; 256];
+    for nat in create_natives().into_values() {
+        natives[nat as usize] = Some(nat);
+    }
+    return natives;
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn new(magic: &[u8; 4]) -> Self {
+        let mut bytes = magic.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        return Writer { bytes };
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.bytes.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.usize(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn token(&mut self, token: &Token) {
+        match token {
+            Native(nat) => { self.bytes.push(0); self.bytes.push(*nat as u8) }
+            Control(mode) => { self.bytes.push(1); self.bytes.push(*mode as u8) }
+            Jump(i) => { self.bytes.push(2); self.usize(*i) }
+            Number(x) => { self.bytes.push(3); self.f64(*x) }
+            Bool(x) => { self.bytes.push(4); self.bytes.push(*x as u8) }
+            Str(i) => { self.bytes.push(5); self.usize(*i) }
+            Symbol(i) => { self.bytes.push(6); self.usize(*i) }
+            Var(i) => { self.bytes.push(7); self.usize(*i) }
+            Array(i) => { self.bytes.push(8); self.usize(*i) }
+            Empty => self.bytes.push(9),
+            Local(slot, body) => { self.bytes.push(10); self.usize(*slot); self.usize(*body) }
+            Closure(i) => { self.bytes.push(11); self.usize(*i) }
+            Map(i) => { self.bytes.push(12); self.usize(*i) }
+            Int(x) => { self.bytes.push(13); self.usize(*x as usize) }
+        }
+    }
+}
+
+impl Writer {
+    fn array(&mut self, array: &ArrayData) {
+        match array {
+            ArrayData::Floats(v) => {
+                self.bytes.push(0);
+                self.usize(v.len());
+                for value in v { self.f64(*value) }
+            }
+            ArrayData::Bytes(v) => {
+                self.bytes.push(1);
+                self.usize(v.len());
+                self.bytes.extend_from_slice(v);
+            }
+            ArrayData::Ints(v) => {
+                self.bytes.push(2);
+                self.usize(v.len());
+                for value in v { self.usize(*value as usize) }
+            }
+            ArrayData::Cells(v) => {
+                self.bytes.push(3);
+                self.usize(v.len());
+                for token in v { self.token(token) }
+            }
+        }
+    }
+
+    fn loop_frame(&mut self, frame: &LoopFrame) {
+        match frame {
+            LoopFrame::Counted { from, to, step, count, body, depth, cases } => {
+                self.bytes.push(0);
+                for value in [from, to, step] { self.f64(*value) }
+                for value in [count, body, depth, cases] { self.usize(*value) }
+            }
+            LoopFrame::Infinite { body, depth, cases } => {
+                self.bytes.push(1);
+                for value in [body, depth, cases] { self.usize(*value) }
+            }
+            LoopFrame::While { cond, body, at, depth, cases, testing } => {
+                self.bytes.push(2);
+                for value in [cond, body, at, depth, cases] { self.usize(*value) }
+                self.bytes.push(*testing as u8);
+            }
+            LoopFrame::Until { body, at, depth, cases } => {
+                self.bytes.push(3);
+                for value in [body, at, depth, cases] { self.usize(*value) }
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    natives: Vec<Option<Nat>>,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], magic: &[u8; 4]) -> Result<Self> {
+        let mut input = Reader { bytes, pos: 0, natives: natives_by_code() };
+        if input.take(4)? != magic { return Err(invalid("not a matching glurr file")) }
+        let version = u16::from_le_bytes(input.take(2)?.try_into().unwrap());
+        if version != VERSION { return Err(invalid("unsupported bytecode version")) }
+        return Ok(input);
+    }
+
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.pos + len > self.bytes.len() {
+            return Err(invalid("unexpected end of bytecode"));
+        }
+        self.pos += len;
+        return Ok(&self.bytes[self.pos - len..self.pos]);
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        let bytes = self.take(8)?.try_into().unwrap();
+        return Ok(u64::from_le_bytes(bytes) as usize);
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        let bytes = self.take(8)?.try_into().unwrap();
+        return Ok(f64::from_le_bytes(bytes));
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.usize()?;
+        let bytes = self.take(len)?.to_vec();
+        return String::from_utf8(bytes).map_err(|_| invalid("invalid string"));
+    }
+
+    fn array(&mut self) -> Result<ArrayData> {
+        let tag = self.byte()?;
+        let len = self.usize()?;
+        return Ok(match tag {
+            0 => ArrayData::Floats((0..len).map(|_| self.f64()).collect::<Result<_>>()?),
+            1 => ArrayData::Bytes(self.take(len)?.to_vec()),
+            2 => ArrayData::Ints(
+                (0..len).map(|_| Ok(self.usize()? as i64)).collect::<Result<_>>()?
+            ),
+            3 => ArrayData::Cells((0..len).map(|_| self.token()).collect::<Result<_>>()?),
+            _ => return Err(invalid("unknown array type")),
+        });
+    }
+
+    fn loop_frame(&mut self) -> Result<LoopFrame> {
+        return Ok(match self.byte()? {
+            0 => LoopFrame::Counted {
+                from: self.f64()?,
+                to: self.f64()?,
+                step: self.f64()?,
+                count: self.usize()?,
+                body: self.usize()?,
+                depth: self.usize()?,
+                cases: self.usize()?,
+            },
+            1 => LoopFrame::Infinite {
+                body: self.usize()?,
+                depth: self.usize()?,
+                cases: self.usize()?,
+            },
+            2 => LoopFrame::While {
+                cond: self.usize()?,
+                body: self.usize()?,
+                at: self.usize()?,
+                depth: self.usize()?,
+                cases: self.usize()?,
+                testing: self.byte()? != 0,
+            },
+            3 => LoopFrame::Until {
+                body: self.usize()?,
+                at: self.usize()?,
+                depth: self.usize()?,
+                cases: self.usize()?,
+            },
+            _ => return Err(invalid("unknown loop frame")),
+        });
+    }
+
+    fn token(&mut self) -> Result<Token> {
+        return Ok(match self.byte()? {
+            0 => {
+                let code = self.byte()? as usize;
+                Native(self.natives[code].ok_or_else(|| invalid("unknown native"))?)
+            }
+            1 => Control(match self.byte()? {
+                0 => Mode::Compile,
+                1 => Mode::Def,
+                2 => Mode::Var,
+                3 => Mode::Quote,
+                4 => Mode::Comment,
+                5 => Mode::Locals,
+                6 => Mode::Struct,
+                _ => return Err(invalid("unknown mode")),
+            }),
+            2 => Jump(self.usize()?),
+            3 => Number(self.f64()?),
+            4 => Bool(self.byte()? != 0),
+            5 => Str(self.usize()?),
+            6 => Symbol(self.usize()?),
+            7 => Var(self.usize()?),
+            8 => Array(self.usize()?),
+            9 => Empty,
+            10 => Local(self.usize()?, self.usize()?),
+            11 => Closure(self.usize()?),
+            12 => Map(self.usize()?),
+            13 => Int(self.usize()? as i64),
+            _ => return Err(invalid("unknown token tag")),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::model::Token::*;
+    use super::VirtualMachine;
+
+    const PROGRAM: &str = "
+        struct point x y end-struct
+        var table
+        var cells
+        def scale-by ( n - closure ) { { * } curry } ;
+        map-new table !
+        \"one\" 1i table @ map-set
+        3 allot-cells cells !
+        41i 0 cells @ set
+        3 4 point 1 cells @ set
+        5 scale-by 2 cells @ set
+        1i table @ map-get
+        0 cells @ get 1i +
+        1 cells @ get point.y@
+        6 2 cells @ get invoke
+        0xff 4i shr
+    ";
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("glurr-{}-{}", std::process::id(), name));
+        return path.to_string_lossy().to_string();
+    }
+
+    /// Run the machine and render what it leaves on the stack.
+    fn run(mut vm: VirtualMachine) -> Vec<String> {
+        vm.interpret();
+        return vm.stack.iter().map(|token| match token {
+            Str(i) => vm.strs[*i].clone(),
+            _ => token.to_string(),
+        }).collect();
+    }
+
+    fn from_source(source: &str) -> VirtualMachine {
+        let mut vm = VirtualMachine::new();
+        vm.include("test".to_string(), source.to_string());
+        return vm;
+    }
+
+    fn compile(source: &str, path: &str) {
+        let mut vm = from_source(source);
+        vm.flag_compile = true;
+        vm.interpret();
+        vm.save_program(path).unwrap();
+    }
+
+    #[test]
+    fn compiled_program_runs_the_same() {
+        let path = temp_path("round-trip.glb");
+        compile(PROGRAM, &path);
+        let loaded = VirtualMachine::load_program(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let expected = run(from_source(PROGRAM));
+        assert_eq!(expected, ["one", "42", "4", "30", "15"]);
+        assert_eq!(run(loaded), expected);
+    }
+
+    #[test]
+    fn compiling_is_reproducible() {
+        let (first, second) = (temp_path("first.glb"), temp_path("second.glb"));
+        compile(PROGRAM, &first);
+        compile(PROGRAM, &second);
+        let bytes = (fs::read(&first).unwrap(), fs::read(&second).unwrap());
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+        assert!(bytes.0 == bytes.1);
+    }
+
+    #[test]
+    fn image_resumes_inside_a_counted_loop() {
+        let path = temp_path("loop.gli");
+        let source = format!("{{ i 2 = {{ \"{}\" save-image }} if i }} 0 5 range enumerate", path);
+        let expected = run(from_source(&source));
+        let resumed = VirtualMachine::load_image(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(expected.len(), 5);
+        assert_eq!(run(resumed), expected);
+    }
+}
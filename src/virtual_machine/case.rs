@@ -0,0 +1,109 @@
+use crate::model::{Nat, Token, Token::*};
+use super::VirtualMachine;
+
+impl VirtualMachine {
+    /// Index of the `endof` or `endcase` closing the branch that starts at
+    /// `from`, skipping nested cases and quotations. At the top level the
+    /// closing word may not be parsed yet, so the source is skipped up to
+    /// it. Found targets are cached.
+    pub fn case_target(&mut self, from: usize, target: Nat) -> usize {
+        if let Some(index) = self.case_targets.get(&from) { return *index }
+        let (mut depth, mut braces) = (0, 0);
+        for i in from + 1..self.tokens.len() {
+            match self.tokens[i] {
+                Native(Nat::OpenBrace) => braces += 1,
+                Native(Nat::CloseBrace) if braces == 0 => {
+                    self.panic("case is not closed in this definition")
+                }
+                Native(Nat::CloseBrace) => braces -= 1,
+                _ if braces > 0 => {}
+                Native(Nat::Case) => depth += 1,
+                Native(Nat::EndCase) if depth > 0 => depth -= 1,
+                Native(nat) if nat == target && depth == 0 => {
+                    self.case_targets.insert(from, i);
+                    return i;
+                }
+                _ => {}
+            }
+        }
+        let i = self.skip_source(target, depth, braces);
+        self.case_targets.insert(from, i);
+        return i;
+    }
+
+    /// Skip the unparsed words of the current source up to `target` and
+    /// parse only that. The skipped words never run, so they are counted
+    /// instead of parsed, which would need the modes that running `def`,
+    /// `var`, `(` or `{:` sets up.
+    fn skip_source(&mut self, target: Nat, mut depth: usize, mut braces: usize) -> usize {
+        let level = self.include_stack.len();
+        let (mut comment, mut quoted) = (false, false);
+        while self.include_stack.len() == level && self.has_source() {
+            let raw = &self.current_source()[self.src_pointer()];
+            let native = self.natives.get(raw.as_str()).copied();
+            if comment {
+                comment = native != Some(Nat::CloseParen);
+            } else if quoted {
+                quoted = false;
+            } else {
+                match native {
+                    Some(Nat::OpenParen) => comment = true,
+                    Some(Nat::Quote) => quoted = true,
+                    Some(Nat::OpenBrace) => braces += 1,
+                    Some(Nat::CloseBrace) if braces == 0 => self.panic("case is not closed"),
+                    Some(Nat::CloseBrace) => braces -= 1,
+                    _ if braces > 0 => {}
+                    Some(Nat::Case) => depth += 1,
+                    Some(Nat::EndCase) if depth > 0 => depth -= 1,
+                    Some(nat) if nat == target && depth == 0 => {
+                        self.parse_ahead();
+                        return self.tokens.len() - 1;
+                    }
+                    _ => {}
+                }
+            }
+            self.move_src_pointer();
+        }
+        self.panic("case is not closed");
+    }
+
+    /// Whether the value before `of` selects its branch. Strings match by
+    /// content, like map keys.
+    pub fn case_matches(&self, test: Token, selector: Token) -> bool {
+        if let (Str(a), Str(b)) = (test, selector) { return self.strs[a] == self.strs[b] }
+        return test == selector;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::Token::*;
+    use super::VirtualMachine;
+
+    /// Run `source` and return the strings it leaves on the stack.
+    fn strings(source: &str) -> Vec<String> {
+        let mut vm = VirtualMachine::new();
+        vm.include("test".to_string(), source.to_string());
+        vm.interpret();
+        return vm.stack.iter().map(|token| match token {
+            Str(i) => vm.strs[*i].clone(),
+            _ => panic!("expected only strings on the stack"),
+        }).collect();
+    }
+
+    #[test]
+    fn top_level_branches_skip_unparsed_source() {
+        let source = "3 case 1 of ( a comment ) \"one\" endof 3 of \"three\" endof endcase";
+        assert_eq!(strings(source), ["three"]);
+        let source = "1 case 1 of \"one\" endof 2 of ( endcase ) ' endcase drop \"two\" endof endcase";
+        assert_eq!(strings(source), ["one"]);
+    }
+
+    #[test]
+    fn leave_drops_the_cases_of_its_loop() {
+        let source = "7 case 5 of \"five\" endof \
+                      { i case 3 of leave endof endcase } 0 10 range enumerate \
+                      7 of \"seven\" endof \"default\" endcase";
+        assert_eq!(strings(source), ["seven"]);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::model::{Token, Token::*};
+use super::VirtualMachine;
+
+/// Heap entry behind a `Closure` token.
+#[derive(Clone, Copy)]
+pub enum Closure {
+    /// Push the value, then call the callable.
+    Curry(Token, Token),
+    /// Call the first callable, then the second.
+    Compose(Token, Token),
+}
+
+impl VirtualMachine {
+    pub fn alloc_closure(&mut self, closure: Closure) -> Token {
+        self.allocations += 1;
+        if let Some(i) = self.free_closures.pop() {
+            self.closures[i] = closure;
+            return Closure(i);
+        }
+        self.closures.push(closure);
+        return Closure(self.closures.len() - 1);
+    }
+
+    /// Call a jump or closure from the token stream, returning the index to
+    /// continue at.
+    pub fn invoke(&mut self, callable: Token) -> usize {
+        match callable {
+            Jump(jump) => return self.call(jump),
+            Closure(i) => match self.closures[i] {
+                Closure::Curry(value, inner) => {
+                    self.stack.push(value);
+                    return self.invoke(inner);
+                }
+                Closure::Compose(first, second) => {
+                    self.roots.push(callable);
+                    self.run_callable(first);
+                    self.roots.pop();
+                    return self.invoke(second);
+                }
+            },
+            _ => self.panic("expected a jump or closure"),
+        }
+    }
+
+    /// Call a jump or closure from a native and wait for it to return,
+    /// keeping it on `roots` meanwhile.
+    pub fn run_callable(&mut self, callable: Token) {
+        self.roots.push(callable);
+        match callable {
+            Jump(jump) => self.run_quotation(jump),
+            Closure(i) => match self.closures[i] {
+                Closure::Curry(value, inner) => {
+                    self.stack.push(value);
+                    self.run_callable(inner);
+                }
+                Closure::Compose(first, second) => {
+                    self.run_callable(first);
+                    self.run_callable(second);
+                }
+            },
+            _ => self.panic("expected a jump or closure"),
+        }
+        self.roots.pop();
+    }
+
+    pub fn serialize_closure(&self, i: usize) -> String {
+        return match self.closures[i] {
+            Closure::Curry(value, inner) => format!("[{} {} curry]",
+                self.serialize_token(&value), self.serialize_token(&inner)),
+            Closure::Compose(first, second) => format!("[{} {} compose]",
+                self.serialize_token(&first), self.serialize_token(&second)),
+        };
+    }
+}
@@ -0,0 +1,170 @@
+use crate::model::{Token, Token::*};
+use super::VirtualMachine;
+
+/// Return address marking the end of a quotation called from a native.
+pub const NATIVE_RETURN: usize = usize::MAX;
+
+impl VirtualMachine {
+    /// Run the quotation at `jump` to completion and come back to the
+    /// native that called it. Handles the native still needs are on `roots`.
+    pub fn run_quotation(&mut self, jump: usize) {
+        let at = self.index;
+        self.ctrl.push(Jump(NATIVE_RETURN));
+        self.index = jump;
+        while self.index != NATIVE_RETURN {
+            if self.index < self.tokens.len() {
+                let token = self.tokens[self.index];
+                self.index = self.evaluate(token);
+            } else if self.has_source() {
+                self.parse_next();
+            } else {
+                self.panic("quotation ended without returning");
+            }
+            self.maybe_collect();
+        }
+        self.index = at;
+    }
+
+    pub fn times(&mut self) {
+        let callable = self.pop_callable();
+        let count = self.pop_index();
+        for _ in 0..count.max(0.) as usize { self.run_callable(callable) }
+    }
+
+    /// Element `i` of an array a quotation may have shrunk since the native
+    /// read its length.
+    fn element(&self, array_ref: usize, i: usize) -> Token {
+        let i = self.array_index(array_ref, i as f64);
+        return self.arrays[array_ref].get(i);
+    }
+
+    pub fn each(&mut self) {
+        let callable = self.pop_callable();
+        let array_ref = self.pop_array();
+        self.roots.push(Array(array_ref));
+        for i in 0..self.arrays[array_ref].len() {
+            self.stack.push(self.element(array_ref, i));
+            self.run_callable(callable);
+        }
+        self.roots.pop();
+    }
+
+    pub fn map(&mut self) {
+        let callable = self.pop_callable();
+        let array_ref = self.pop_array();
+        let mapped = self.alloc_array(self.arrays[array_ref].empty_like());
+        let Array(mapped_ref) = mapped else { unreachable!() };
+        self.roots.extend([Array(array_ref), mapped]);
+        for i in 0..self.arrays[array_ref].len() {
+            self.stack.push(self.element(array_ref, i));
+            self.run_callable(callable);
+            let value = self.pop_element(array_ref);
+            self.arrays[mapped_ref].push(value);
+        }
+        self.roots.truncate(self.roots.len() - 2);
+        self.stack.push(mapped);
+    }
+
+    pub fn filter(&mut self) {
+        let callable = self.pop_callable();
+        let array_ref = self.pop_array();
+        let kept = self.alloc_array(self.arrays[array_ref].empty_like());
+        let Array(kept_ref) = kept else { unreachable!() };
+        self.roots.extend([Array(array_ref), kept]);
+        for i in 0..self.arrays[array_ref].len() {
+            let value = self.element(array_ref, i);
+            self.stack.push(value);
+            self.run_callable(callable);
+            if self.pop_bool() { self.arrays[kept_ref].push(value) }
+        }
+        self.roots.truncate(self.roots.len() - 2);
+        self.stack.push(kept);
+    }
+
+    pub fn fold(&mut self) {
+        let callable = self.pop_callable();
+        let init = self.pop_token();
+        let array_ref = self.pop_array();
+        self.stack.push(init);
+        self.roots.push(Array(array_ref));
+        for i in 0..self.arrays[array_ref].len() {
+            self.stack.push(self.element(array_ref, i));
+            self.run_callable(callable);
+        }
+        self.roots.pop();
+    }
+
+    pub fn reduce(&mut self) {
+        let callable = self.pop_callable();
+        let array_ref = self.pop_array();
+        if self.arrays[array_ref].len() == 0 {
+            self.panic("reduce requires a non-empty array")
+        }
+        self.stack.push(self.arrays[array_ref].get(0));
+        self.roots.push(Array(array_ref));
+        for i in 1..self.arrays[array_ref].len() {
+            self.stack.push(self.element(array_ref, i));
+            self.run_callable(callable);
+        }
+        self.roots.pop();
+    }
+
+    /// Apply each of `count` quotations to a copy of the value below them.
+    pub fn cleave(&mut self, count: usize) {
+        let mut callables: Vec<Token> = (0..count).map(|_| self.pop_callable()).collect();
+        callables.reverse();
+        let value = self.pop_token();
+        let base = self.roots.len();
+        self.roots.push(value);
+        self.roots.extend(&callables);
+        for callable in callables {
+            self.stack.push(value);
+            self.run_callable(callable);
+        }
+        self.roots.truncate(base);
+    }
+
+    pub fn keep(&mut self) {
+        let callable = self.pop_callable();
+        let value = self.pop_token();
+        self.stack.push(value);
+        self.roots.push(value);
+        self.run_callable(callable);
+        self.roots.pop();
+        self.stack.push(value);
+    }
+
+    pub fn dip(&mut self) {
+        let callable = self.pop_callable();
+        let value = self.pop_token();
+        self.roots.push(value);
+        self.run_callable(callable);
+        self.roots.pop();
+        self.stack.push(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::Token::*;
+    use super::VirtualMachine;
+
+    /// Run `setup`, then `source` inside `catch`, and return the error raised.
+    fn error(setup: &str, source: &str) -> String {
+        let mut vm = VirtualMachine::new();
+        vm.include("test".to_string(), format!("{} {{ {} }} catch", setup, source));
+        vm.interpret();
+        let Some(Str(i)) = vm.stack.last() else { panic!("nothing was raised") };
+        return vm.strs[*i].clone();
+    }
+
+    #[test]
+    fn quotations_shrinking_the_array_raise() {
+        let setup = "var a vec-new a ! 1 a @ push 2 a @ push 3 a @ push";
+        for source in ["a @ { drop a @ pop drop } each", "a @ { a @ pop drop } map",
+            "a @ { a @ pop drop true } filter", "a @ 0 { + a @ pop drop } fold",
+            "a @ { + a @ pop drop } reduce"] {
+            assert_eq!(error(setup, source), "array index out of bounds");
+        }
+    }
+}
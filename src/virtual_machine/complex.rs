@@ -0,0 +1,44 @@
+use crate::model::Token::*;
+use super::VirtualMachine;
+
+/// Complex numbers are two stack values, the real part below the imaginary.
+impl VirtualMachine {
+    fn pop_complex(&mut self) -> (f64, f64) {
+        let im = self.pop_num();
+        let re = self.pop_num();
+        return (re, im);
+    }
+
+    fn push_complex(&mut self, (re, im): (f64, f64)) {
+        self.stack.push(Number(re));
+        self.stack.push(Number(im));
+    }
+
+    pub fn complex_add(&mut self) {
+        let (c, d) = self.pop_complex();
+        let (a, b) = self.pop_complex();
+        self.push_complex((a + c, b + d));
+    }
+
+    pub fn complex_mul(&mut self) {
+        let (c, d) = self.pop_complex();
+        let (a, b) = self.pop_complex();
+        self.push_complex((a * c - b * d, a * d + b * c));
+    }
+
+    pub fn complex_sq(&mut self) {
+        let (a, b) = self.pop_complex();
+        self.push_complex((a * a - b * b, a * b + b * a));
+    }
+
+    /// Squared magnitude, which avoids the square root of `abs`.
+    pub fn complex_abs2(&mut self) {
+        let (a, b) = self.pop_complex();
+        self.stack.push(Number(a * a + b * b));
+    }
+
+    pub fn complex_conj(&mut self) {
+        let (a, b) = self.pop_complex();
+        self.push_complex((a, -b));
+    }
+}
@@ -0,0 +1,43 @@
+use super::{DictEntry, VirtualMachine};
+
+impl VirtualMachine {
+    pub fn find_symbol(&self, name: &str) -> Option<usize> {
+        return self.sym_index.get(name).copied();
+    }
+
+    pub fn add_symbol(&mut self, name: &str) -> usize {
+        // natives are looked up first, so the word could never be called
+        if self.natives.contains_key(name) {
+            self.raise("cannot redefine a native word");
+            self.print_trace();
+            println!("\x1b[91m'{}' is a native word\x1b[0m", name);
+            self.panic("cannot redefine a native word");
+        }
+        if self.sym_index.contains_key(name) {
+            self.raise("symbol already defined");
+            self.print_trace();
+            println!("\x1b[91msymbol '{}' already defined\x1b[0m", name);
+            self.panic("symbol already defined");
+        }
+        self.syms.push(name.to_string());
+        self.sym_index.insert(name.to_string(), self.syms.len() - 1);
+        return self.syms.len() - 1;
+    }
+
+    pub fn define(&mut self, symbol: usize, jump: usize) {
+        self.dict.push(DictEntry { symbol, jump });
+        self.words.insert(symbol, self.dict.len() - 1);
+        self.word_names.insert(jump, self.dict.len() - 1);
+    }
+
+    pub fn lookup_word(&self, name: &str) -> Option<usize> {
+        let symbol = self.find_symbol(name)?;
+        let entry = self.words.get(&symbol)?;
+        return Some(self.dict[*entry].jump);
+    }
+
+    pub fn word_name(&self, jump: usize) -> Option<&String> {
+        let entry = self.word_names.get(&jump)?;
+        return Some(&self.syms[self.dict[*entry].symbol]);
+    }
+}
@@ -1,12 +1,26 @@
 use image::ImageBuffer;
 use crate::stack::Stack;
-use crate::model::{self, Nat, Mode, Token, Token::*};
+use crate::model::{self, LoopFrame, Nat, Mode, Token, Token::*};
 use super::VirtualMachine;
+use super::arrays::ArrayData;
+use super::closures::Closure;
+use super::combinators::NATIVE_RETURN;
+use super::maps::MapData;
 
 impl VirtualMachine {
     pub fn evaluate_native(&mut self, native: Nat) -> usize {
         use model::Nat::*;
+        if let Some(Int(_)) = self.stack.last() {
+            if self.int_native(native) { return self.index + 1 }
+        }
         match native {
+            // compiling runs the program for the code it shapes, without output
+            Dots | CtrlDots if self.flag_compile => {}
+            Dot | SaveImage if self.flag_compile => { self.pop_token(); }
+            DisplayImage if self.flag_compile => {
+                self.pop_index();
+                self.pop_array();
+            }
             Plus => {
                 let sum = self.pop_num() + self.pop_num();
                 self.stack.push(Token::Number(sum));
@@ -77,11 +91,12 @@ impl VirtualMachine {
                 self.stack.push(c);
             }
             Pick => {
-                let offset = self.pop_num();
-                let index = self.stack.len() - offset as usize;
-                if let Some(token) = self.stack.get(index) {
-                    self.stack.push(token.clone());
+                let offset = self.pop_index();
+                let index = self.stack.len().checked_sub(offset as usize);
+                if let Some(token) = index.and_then(|i| self.stack.get(i)) {
+                    self.stack.push(*token);
                 } else {
+                    self.raise("stack is empty");
                     self.print_trace();
                     self.panic("stack is empty");
                 }
@@ -100,6 +115,7 @@ impl VirtualMachine {
             }
             Include => {
                 let str_i = self.pop_str();
+                if self.precompiled { return self.index + 1 }
                 let name = self.strs[str_i].clone();
                 let Some(content) = self.includeables.get(&name).cloned() else {
                     self.panic("include not listed at startup")
@@ -108,22 +124,80 @@ impl VirtualMachine {
             }
             Debug => {}
             Def => {
-                self.ctrl.push(Control(Mode::Def));
+                if !self.precompiled { self.ctrl.push(Control(Mode::Def)) }
             }
             Var => {
-                self.ctrl.push(Control(Mode::Var));
-            }
+                if !self.precompiled { self.ctrl.push(Control(Mode::Var)) }
+            }
+            Struct => {
+                if !self.precompiled { self.ctrl.push(Control(Mode::Struct)) }
+            }
+            EndStruct => self.panic("end-struct without struct"),
+            NewStruct => self.new_struct(),
+            MapNew => {
+                let map = self.alloc_map(MapData::new());
+                self.stack.push(map);
+            }
+            MapSet => self.map_set(),
+            MapGet => self.map_get(),
+            MapHas => self.map_has(),
+            MapDelete => self.map_delete(),
+            MapKeys => self.map_keys(),
+            MapEach => self.map_each(),
+            VecNew => {
+                let array = self.alloc_array(ArrayData::Cells(Vec::new()));
+                self.stack.push(array);
+            }
+            Push => self.push_element(),
+            Pop => self.pop_last(),
+            Insert => self.insert(),
+            Remove => self.remove(),
+            Slice => self.slice(),
+            Sort => self.sort(),
+            And | Or | Xor => self.logic(native),
+            Shl => self.shift(true),
+            Shr => self.shift(false),
+            // only reached without an int on top, which is an error
+            Invert => { self.pop_int(); }
+            IntToFloat => self.int_to_float(),
+            FloatToInt => self.float_to_int(),
+            Sqrt => self.unary(f64::sqrt),
+            Sin => self.unary(f64::sin),
+            Cos => self.unary(f64::cos),
+            Tan => self.unary(f64::tan),
+            Atan2 => self.binary(f64::atan2),
+            Exp => self.unary(f64::exp),
+            Ln => self.unary(f64::ln),
+            Log10 => self.unary(f64::log10),
+            Min => self.binary(f64::min),
+            Max => self.binary(f64::max),
+            Clamp => self.clamp(),
+            Lerp => self.lerp(),
+            Pi => self.stack.push(Number(std::f64::consts::PI)),
+            E => self.stack.push(Number(std::f64::consts::E)),
+            IsNan => self.classify(f64::is_nan),
+            IsInf => self.classify(f64::is_infinite),
+            Seed => self.seed(),
+            Random => self.random(),
+            RandomInt => self.random_int(),
+            Shuffle => self.shuffle(),
+            ComplexAdd => self.complex_add(),
+            ComplexMul => self.complex_mul(),
+            ComplexSq => self.complex_sq(),
+            ComplexAbs2 => self.complex_abs2(),
+            Conj => self.complex_conj(),
             Consume => todo!("todo"),
             Quote => self.ctrl.push(Control(Mode::Quote)),
             Emit => {
                 let token = self.pop_token();
-                self.tokens.push(token);
+                if !self.precompiled { self.tokens.push(token) }
             },
             OpenBrace => {
                 self.ctrl.push(Control(Mode::Compile));
                 self.stack.push(Jump(self.index + 1));
             }
             CloseBrace => {
+                self.release_locals();
                 if let Some(Jump(index)) = self.ctrl.pop() {
                     return index;
                 }
@@ -133,10 +207,11 @@ impl VirtualMachine {
             Semicolon => {
                 if let Some(Jump(jump)) = self.stack.pop() {
                     if let Some(Symbol(symb_i)) = self.stack.pop() {
-                        self.dict.push(super::DictEntry {
-                            symbol: symb_i,
-                            jump: jump
-                        })
+                        if !self.precompiled {
+                            self.define(symb_i, jump);
+                            self.optimize_word(jump);
+                        }
+                        if self.flag_threaded { self.compile_word(jump) }
                     } else { self.panic("; requires a symbol") }
                 } else { self.panic("; requires a jump") }
             }
@@ -144,24 +219,23 @@ impl VirtualMachine {
                 let token = self.pop_token();
                 self.ctrl.push(token);
             },
-            ReadCtrl => self.stack.push(self.ctrl.pop_token()),
+            ReadCtrl => {
+                let token = self.pop_ctrl();
+                self.stack.push(token);
+            }
             CopyCtrl => {
-                let val = self.ctrl.pop_token();
-                self.ctrl.push(val.clone());
-                self.stack.push(val.clone());
+                let val = self.pop_ctrl();
+                self.ctrl.push(val);
+                self.stack.push(val);
             },
             Invoke => {
-                let jump = self.pop_jump();
-                self.ctrl.push(Jump(self.index + 1));
-                return jump;
-            }
-            Allot => {
-                let len = self.pop_num() as usize;
-                let mut array: Vec<f64> = Vec::with_capacity(len);
-                for _ in 0..len { array.push(0.) }
-                self.arrays.push(array);
-                self.stack.push(Array(self.arrays.len() - 1))
+                let callable = self.pop_callable();
+                return self.invoke(callable);
             }
+            Allot => self.allot(|len| ArrayData::Floats(vec![0.; len])),
+            AllotBytes => self.allot(|len| ArrayData::Bytes(vec![0; len])),
+            AllotInts => self.allot(|len| ArrayData::Ints(vec![0; len])),
+            AllotCells => self.allot(|len| ArrayData::Cells(vec![Number(0.); len])),
             JumpAsNumber => {
                 let jump = self.pop_jump();
                 self.stack.push(Number(jump as f64))
@@ -195,30 +269,35 @@ impl VirtualMachine {
                 self.stack.push(Array(num as usize))
             },
             Set => {
-                let array_ref = self.pop_array() as usize;
-                let index = self.pop_num() as usize;
-                let value = self.pop_num() as f64;
-                let array = &mut self.arrays[array_ref];
-                array[index] = value;
+                let array_ref = self.pop_array();
+                let index = self.pop_index();
+                let value = self.pop_element(array_ref);
+                let index = self.array_index(array_ref, index);
+                self.arrays[array_ref].set(index, value);
             }
             Get => {
-                let array_ref = self.pop_array() as usize;
-                let index = self.pop_num() as usize;
-                let array = &mut self.arrays[array_ref];
-                self.stack.push(Number(array[index] as f64));
+                let array_ref = self.pop_array();
+                let index = self.pop_index();
+                let index = self.array_index(array_ref, index);
+                self.stack.push(self.arrays[array_ref].get(index));
             }
             DisplayImage => {
-                let width = self.pop_num() as u32;
+                let width = self.pop_index() as u32;
                 let array_ref = self.pop_array();
-                let array = &mut self.arrays[array_ref];
+                if width == 0 { self.panic("image width must be positive") }
+                let array = &self.arrays[array_ref];
+                let channel = |i: usize| match array {
+                    ArrayData::Bytes(bytes) => bytes[i],
+                    array => match array.get(i) {
+                        Number(value) => value as u8,
+                        Int(value) => value.clamp(0, 255) as u8,
+                        _ => 0,
+                    },
+                };
                 let height = array.len() as u32 / (width * 4);
                 let img = ImageBuffer::from_fn(width, height, |x, y| {
                     let i = 4 * (x + width * y) as usize;
-                    image::Rgb([
-                        array[i+0] as u8,
-                        array[i+1] as u8,
-                        array[i+2] as u8
-                    ])
+                    image::Rgb([channel(i), channel(i+1), channel(i+2)])
                 });
                 let res = img.save("./output.png");
                 res.expect("failed to write image")
@@ -230,63 +309,160 @@ impl VirtualMachine {
                 self.stack.push(if cond { true_val } else { false_val });
             }
             If => {
-                let jump = self.pop_jump();
+                let callable = self.pop_callable();
                 let cond = self.pop_bool();
-                if cond {
-                    self.ctrl.push(Jump(self.index + 1));
-                    return jump
-                }
+                if cond { return self.invoke(callable) }
+            },
+            IfElse => {
+                let false_callable = self.pop_callable();
+                let true_callable = self.pop_callable();
+                let cond = self.pop_bool();
+                return self.invoke(if cond { true_callable } else { false_callable })
             },
+            Case => {
+                let selector = self.pop_token();
+                self.cases.push(selector);
+            }
+            Of => {
+                let test = self.pop_token();
+                let Some(selector) = self.cases.last().copied() else {
+                    self.panic("of outside of case")
+                };
+                if !self.case_matches(test, selector) {
+                    return self.case_target(self.index, EndOf) + 1
+                }
+            }
+            EndOf => return self.case_target(self.index, EndCase),
+            EndCase => {
+                if self.cases.pop().is_none() { self.panic("endcase outside of case") }
+            }
             Infinite => {
-                self.loops.push(Number(0.));
-                self.loops.push(Number(0.));
-                let jmp = self.pop_jump();
-                self.loops.push(Jump(jmp));
+                let body = self.pop_jump();
+                let (depth, cases) = (self.ctrl.len(), self.cases.len());
+                self.loops.push(LoopFrame::Infinite { body, depth, cases });
             }
             Loop => {
-                let jump = self.loops.pop_jump();
-                self.loops.push(Jump(jump));
+                let Some(LoopFrame::Infinite { body, .. }) = self.loops.last() else {
+                    self.panic("loop requires infinite")
+                };
+                let body = *body;
                 self.ctrl.push(Jump(self.index));
-                return jump
+                return body
             }
             Range => {
-                let to = self.pop_num();
-                let from = self.pop_num();
-                let jmp = self.pop_jump();
-                self.loops.push(Number(to));
-                self.loops.push(Number(from));
-                self.loops.push(Jump(jmp));
+                let to = self.pop_index();
+                let from = self.pop_index();
+                let body = self.pop_jump();
+                let (depth, cases) = (self.ctrl.len(), self.cases.len());
+                self.loops.push(LoopFrame::Counted { from, to, step: 1., count: 0, body, depth, cases });
+            }
+            RangeStep => {
+                let step = self.pop_index();
+                let to = self.pop_index();
+                let from = self.pop_index();
+                let body = self.pop_jump();
+                if step == 0. || step.is_nan() { self.panic("range-step requires a nonzero step") }
+                let (depth, cases) = (self.ctrl.len(), self.cases.len());
+                self.loops.push(LoopFrame::Counted { from, to, step, count: 0, body, depth, cases });
             }
             Enumerate => {
-                let jump = self.loops.pop_jump();
-                let from = self.loops.pop_num();
-                let to = self.loops.pop_num();
-                if from < to {
-                    self.loops.push(Number(to));
-                    self.loops.push(Number(from + 1.));
-                    self.loops.push(Jump(jump));
+                let Some(LoopFrame::Counted { from, to, step, count, body, .. }) =
+                    self.loops.last_mut() else {
+                    self.panic("enumerate requires range")
+                };
+                let next = *from + *count as f64 * *step;
+                if if *step > 0. { next < *to } else { next > *to } {
+                    *count += 1;
+                    let body = *body;
                     self.ctrl.push(Jump(self.index));
-                    return jump
+                    return body
                 }
+                self.loops.pop();
+            }
+            While => return self.step_while(),
+            Until => return self.step_until(),
+            Leave => return self.leave(),
+            Times => self.times(),
+            Each => self.each(),
+            Map => self.map(),
+            Filter => self.filter(),
+            Fold => self.fold(),
+            Reduce => self.reduce(),
+            Bi => self.cleave(2),
+            Tri => self.cleave(3),
+            Keep => self.keep(),
+            Dip => self.dip(),
+            Curry => {
+                let callable = self.pop_callable();
+                let value = self.pop_token();
+                let closure = self.alloc_closure(Closure::Curry(value, callable));
+                self.stack.push(closure);
+            }
+            Concat => self.concat(),
+            Length => self.length(),
+            Substring => self.substring(),
+            IndexOf => self.index_of(),
+            Split => self.split(),
+            ToUpper => self.upper_case(),
+            ToLower => self.lower_case(),
+            StrEqual => self.compare_strings(false),
+            StrLessThan => self.compare_strings(true),
+            NumberToString => self.number_to_string(),
+            StringToNumber => self.string_to_number(),
+            Gc => self.collect(),
+            ArrayLength => {
+                let array_ref = self.pop_array();
+                self.stack.push(Number(self.arrays[array_ref].len() as f64));
+            }
+            Resize => self.resize(),
+            Fill => self.fill(),
+            Compose => {
+                let second = self.pop_callable();
+                let first = self.pop_callable();
+                let closure = self.alloc_closure(Closure::Compose(first, second));
+                self.stack.push(closure);
             }
             LeaveIf => {
-                if self.pop_bool() {
-                    let jump = self.ctrl.pop_jump();
-                    self.loops.pop_jump();
-                    self.loops.pop_num();
-                    self.loops.pop_num();
-                    return jump + 1
-                }
+                if self.pop_bool() { return self.leave() }
             }
             I => {
-                if let Some(Number(i)) = self.loops.get(self.loops.len() - 2) {
-                    self.stack.push(Number(i - 1.));
-                } else {
-                    self.panic("expected a number");
-                }
+                let i = self.loop_index(0);
+                self.stack.push(Number(i));
+            }
+            J => {
+                let j = self.loop_index(1);
+                self.stack.push(Number(j));
+            }
+            K => {
+                let k = self.loop_index(2);
+                self.stack.push(Number(k));
+            }
+            OpenParen => {
+                if !self.precompiled { self.ctrl.push(Control(Mode::Comment)) }
             }
-            OpenParen => { self.ctrl.push(Control(Mode::Comment)) }
             CloseParen => self.panic("unexpected CloseParen"),
+            Recurse => self.panic("unexpected Recurse"),
+            OpenLocals => self.panic("unexpected OpenLocals"),
+            CloseLocals => self.panic("unexpected CloseLocals"),
+            BindLocals => {
+                let count = self.pop_num() as usize;
+                let body = self.pop_num() as usize;
+                self.bind_locals(count, body);
+            }
+            Catch => {
+                let callable = self.pop_callable();
+                self.catch(callable);
+            }
+            Throw => {
+                let token = self.pop_token();
+                if token != Number(0.) { self.throw(token) }
+            }
+            WriteLocal => {
+                let (slot, body) = self.next_local();
+                let token = self.pop_token();
+                self.write_local(slot, body, token);
+                return self.index + 2;
+            }
             Dot => {
                 let token = self.pop_token();
                 match token {
@@ -334,6 +510,16 @@ impl VirtualMachine {
                     self.serialize_token(token);
                 }
             }
+            SaveImage => {
+                let str_i = self.pop_str();
+                // natives running a quotation keep their state on the Rust stack
+                if self.catching > 0 || self.ctrl.contains(&Jump(NATIVE_RETURN)) {
+                    self.panic("save-image inside a quotation run by a native");
+                }
+                let path = self.strs[str_i].clone();
+                let res = self.save_image(&path, self.index + 1);
+                res.expect("failed to save image")
+            }
         }
         return self.index + 1;
     }
@@ -0,0 +1,64 @@
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+
+use crate::model::{Token, Token::*};
+use super::VirtualMachine;
+
+/// Unwinding payload carrying a thrown value or a VM error to `catch`.
+pub enum Thrown {
+    Value(Token),
+    Error(&'static str),
+}
+
+impl VirtualMachine {
+    /// Run the quotation or closure, leaving 0 on the stack if it returns
+    /// normally. If something is thrown the stacks are cut back to their
+    /// height at the catch and the thrown value or error message is left.
+    pub fn catch(&mut self, callable: Token) {
+        let at = self.index;
+        let (stack, ctrl, loops) = (self.stack.len(), self.ctrl.len(), self.loops.len());
+        let (frames, returns) = (self.frames.len(), self.code_returns.len());
+        let (cases, roots) = (self.cases.len(), self.roots.len());
+        self.catching += 1;
+        let result = catch_unwind(AssertUnwindSafe(|| self.run_callable(callable)));
+        self.catching -= 1;
+        self.index = at;
+        let thrown = match result {
+            Ok(()) => {
+                self.stack.push(Number(0.));
+                return;
+            }
+            Err(payload) => match payload.downcast::<Thrown>() {
+                Ok(thrown) => *thrown,
+                Err(payload) => resume_unwind(payload),
+            },
+        };
+        self.stack.truncate(stack);
+        self.ctrl.truncate(ctrl);
+        self.loops.truncate(loops);
+        self.cases.truncate(cases);
+        self.roots.truncate(roots);
+        self.code_returns.truncate(returns);
+        if let Some(frame) = self.frames.get(frames) {
+            self.locals.truncate(frame.base);
+        }
+        self.frames.truncate(frames);
+        match thrown {
+            Thrown::Value(token) => self.stack.push(token),
+            Thrown::Error(msg) => {
+                let token = self.alloc_str(msg.to_string());
+                self.stack.push(token);
+            }
+        }
+    }
+
+    pub fn throw(&self, token: Token) -> ! {
+        if self.catching > 0 { resume_unwind(Box::new(Thrown::Value(token))) }
+        println!("\x1b[91muncaught throw {}\x1b[0m", self.serialize_token(&token));
+        self.panic("uncaught throw");
+    }
+
+    /// Hand a VM error to the innermost `catch`, if there is one.
+    pub fn raise(&self, msg: &'static str) {
+        if self.catching > 0 { resume_unwind(Box::new(Thrown::Error(msg))) }
+    }
+}
@@ -0,0 +1,183 @@
+use std::cmp::max;
+
+use crate::model::{Token, Token::*};
+use super::arrays::ArrayData;
+use super::closures::Closure;
+use super::threaded::Op;
+use super::VirtualMachine;
+
+/// Allocations before the first automatic collection.
+pub const GC_MIN_THRESHOLD: usize = 4096;
+
+impl VirtualMachine {
+    pub fn alloc_str(&mut self, value: String) -> Token {
+        self.allocations += 1;
+        if let Some(i) = self.free_strs.pop() {
+            self.strs[i] = value;
+            return Str(i);
+        }
+        self.strs.push(value);
+        return Str(self.strs.len() - 1);
+    }
+
+    pub fn alloc_array(&mut self, array: ArrayData) -> Token {
+        self.allocations += 1;
+        if let Some(i) = self.free_arrays.pop() {
+            self.arrays[i] = array;
+            return Array(i);
+        }
+        self.arrays.push(array);
+        return Array(self.arrays.len() - 1);
+    }
+
+    /// Collect once enough has been allocated since the last collection.
+    /// Only called between tokens, where natives running a quotation keep
+    /// the handles they have popped on `roots`.
+    pub fn maybe_collect(&mut self) {
+        if self.allocations >= self.gc_threshold { self.collect(); }
+    }
+
+    /// Free every string, array, map and closure not reachable from the
+    /// stacks, variables, locals, roots or the token stream. Freed slots are reused
+    /// by later allocations. Handles hidden in numbers with `string-as-number` or
+    /// `array-as-number` are not traced.
+    pub fn collect(&mut self) {
+        let mut marks = Marks {
+            strs: vec![false; self.strs.len()],
+            arrays: vec![false; self.arrays.len()],
+            maps: vec![false; self.maps.len()],
+            closures: vec![false; self.closures.len()],
+            pending: Vec::new(),
+        };
+        let mut mark = |token: &Token| marks.mark(token);
+        let roots = self.stack.iter()
+            .chain(self.ctrl.iter())
+            .chain(self.cases.iter())
+            .chain(self.vars.iter())
+            .chain(self.locals.iter())
+            .chain(self.roots.iter())
+            .chain(self.tokens.iter())
+            .chain(self.originals.values());
+        roots.for_each(&mut mark);
+        for op in &self.code {
+            if let Op::Push(token) = op { mark(token) }
+        }
+        while let Some(token) = marks.pending.pop() {
+            match token {
+                Array(i) => if let ArrayData::Cells(cells) = &self.arrays[i] {
+                    for token in cells { marks.mark(token) }
+                },
+                Map(i) => for token in self.maps[i].values() { marks.mark(token) },
+                Closure(i) => match &self.closures[i] {
+                    Closure::Curry(a, b) | Closure::Compose(a, b) => {
+                        marks.mark(a);
+                        marks.mark(b);
+                    }
+                },
+                _ => {}
+            }
+        }
+        let Marks { strs, arrays, maps, closures, .. } = marks;
+
+        let old_strs = self.free_strs.len();
+        let old_arrays = self.free_arrays.len();
+        let old_maps = self.free_maps.len();
+        let old_closures = self.free_closures.len();
+        self.free_strs = unmarked(&strs);
+        self.free_arrays = unmarked(&arrays);
+        self.free_maps = unmarked(&maps);
+        self.free_closures = unmarked(&closures);
+        for i in &self.free_strs { self.strs[*i] = String::new() }
+        for i in &self.free_arrays {
+            self.arrays[*i] = ArrayData::Floats(Vec::new());
+            self.records.remove(i);
+        }
+        for i in &self.free_maps { self.maps[*i].clear() }
+        for i in &self.free_closures { self.closures[*i] = Closure::Curry(Empty, Empty) }
+
+        let live_strs = strs.len() - self.free_strs.len();
+        let live_arrays = arrays.len() - self.free_arrays.len();
+        let live_maps = maps.len() - self.free_maps.len();
+        let live_closures = closures.len() - self.free_closures.len();
+        self.allocations = 0;
+        self.gc_threshold = max(GC_MIN_THRESHOLD,
+            2 * (live_strs + live_arrays + live_maps + live_closures));
+        if self.flag_report {
+            println!("gc: freed {} strings, {} arrays, {} maps, {} closures; \
+                live {} strings, {} arrays, {} maps, {} closures",
+                self.free_strs.len().saturating_sub(old_strs),
+                self.free_arrays.len().saturating_sub(old_arrays),
+                self.free_maps.len().saturating_sub(old_maps),
+                self.free_closures.len().saturating_sub(old_closures),
+                live_strs, live_arrays, live_maps, live_closures);
+        }
+    }
+}
+
+/// Reachable slots found so far, with the arrays, maps and closures whose
+/// contents are yet to be traced.
+struct Marks {
+    strs: Vec<bool>,
+    arrays: Vec<bool>,
+    maps: Vec<bool>,
+    closures: Vec<bool>,
+    pending: Vec<Token>,
+}
+
+impl Marks {
+    fn mark(&mut self, token: &Token) {
+        match token {
+            Str(i) if *i < self.strs.len() => self.strs[*i] = true,
+            Array(i) if *i < self.arrays.len() && !self.arrays[*i] => {
+                self.arrays[*i] = true;
+                self.pending.push(*token);
+            }
+            Map(i) if *i < self.maps.len() && !self.maps[*i] => {
+                self.maps[*i] = true;
+                self.pending.push(*token);
+            }
+            Closure(i) if *i < self.closures.len() && !self.closures[*i] => {
+                self.closures[*i] = true;
+                self.pending.push(*token);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn unmarked(marks: &[bool]) -> Vec<usize> {
+    return (0..marks.len()).rev().filter(|i| !marks[*i]).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::Token::*;
+    use super::VirtualMachine;
+
+    fn run(source: &str) -> VirtualMachine {
+        let mut vm = VirtualMachine::new();
+        vm.include("test".to_string(), source.to_string());
+        vm.interpret();
+        return vm;
+    }
+
+    #[test]
+    fn collects_inside_quotations_run_by_natives() {
+        let vm = run("{ 20000 { \"a\" \"b\" concat drop } times } catch drop");
+        assert!(vm.strs.len() < 10000);
+    }
+
+    #[test]
+    fn handles_held_by_natives_survive() {
+        let churn = "5000 { \"a\" \"b\" concat drop } times";
+        let vm = run(&format!("3 allot-cells {{ drop {} \"s\" \"t\" concat }} map", churn));
+        let Some(Array(i)) = vm.stack.last() else { panic!("map left no array") };
+        for k in 0..3 {
+            let Str(s) = vm.arrays[*i].get(k) else { panic!("expected a string") };
+            assert_eq!(vm.strs[s], "st");
+        }
+        let vm = run(&format!("\"x\" \"y\" concat {{ {} }} keep", churn));
+        let Some(Str(s)) = vm.stack.last() else { panic!("keep left no string") };
+        assert_eq!(vm.strs[*s], "xy");
+    }
+}
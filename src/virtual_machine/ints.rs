@@ -0,0 +1,198 @@
+use crate::model::{Nat, Token::*};
+use super::VirtualMachine;
+
+/// Parse `42i`, `-7i` or hex like `0xff` as an int literal. Hex digits may
+/// fill all 64 bits, so `0xffffffffffffffff` is -1.
+pub fn parse_int(raw: &str) -> Option<i64> {
+    if let Some(decimal) = raw.strip_suffix('i') {
+        return decimal.parse::<i64>().ok();
+    }
+    let (negative, digits) = match raw.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, raw),
+    };
+    let value = u64::from_str_radix(digits.strip_prefix("0x")?, 16).ok()? as i64;
+    return Some(if negative { value.wrapping_neg() } else { value });
+}
+
+impl VirtualMachine {
+    pub fn pop_int(&mut self) -> i64 {
+        let token = self.stack.pop();
+        if let Some(Int(value)) = token { return value }
+        self.stop("expected an int", token);
+    }
+
+    /// Run the int version of an arithmetic or comparison native when an
+    /// int is on top of the stack. Ints never mix with floats, so the value
+    /// below must be an int too. `+ - * neg abs` and `shl` wrap around on
+    /// overflow, as does `/` for the smallest int divided by -1. `/`
+    /// truncates toward zero and `mod` is never negative.
+    /// Returns false for natives without an int version.
+    pub fn int_native(&mut self, native: Nat) -> bool {
+        use Nat::*;
+        match native {
+            Plus | Minus | Multiply | Divide | Mod | Equal | GreaterThan | LessThan |
+            And | Or | Xor | Min | Max => {
+                let rhs = self.pop_int();
+                let lhs = self.pop_int();
+                let result = match native {
+                    Plus => Int(lhs.wrapping_add(rhs)),
+                    Minus => Int(lhs.wrapping_sub(rhs)),
+                    Multiply => Int(lhs.wrapping_mul(rhs)),
+                    Divide | Mod if rhs == 0 => self.panic("division by zero"),
+                    Divide => Int(lhs.wrapping_div(rhs)),
+                    Mod => Int(lhs.wrapping_rem_euclid(rhs)),
+                    Equal => Bool(lhs == rhs),
+                    GreaterThan => Bool(lhs > rhs),
+                    LessThan => Bool(lhs < rhs),
+                    And => Int(lhs & rhs),
+                    Or => Int(lhs | rhs),
+                    Min => Int(lhs.min(rhs)),
+                    Max => Int(lhs.max(rhs)),
+                    _ => Int(lhs ^ rhs),
+                };
+                self.stack.push(result);
+            }
+            Clamp => {
+                let hi = self.pop_int();
+                let lo = self.pop_int();
+                let value = self.pop_int();
+                if lo > hi { self.panic("clamp bounds are reversed") }
+                self.stack.push(Int(value.clamp(lo, hi)));
+            }
+            Neg | Abs | Floor | Ceil | Round | Invert => {
+                let value = self.pop_int();
+                self.stack.push(Int(match native {
+                    Neg => value.wrapping_neg(),
+                    Abs => value.wrapping_abs(),
+                    Invert => !value,
+                    _ => value,
+                }));
+            }
+            _ => return false,
+        }
+        return true;
+    }
+
+    /// `shl` drops bits shifted out on the left, `shr` keeps the sign.
+    /// Shift amounts must be between 0 and 63.
+    pub fn shift(&mut self, left: bool) {
+        let amount = self.pop_int();
+        let value = self.pop_int();
+        if !(0..64).contains(&amount) { self.panic("shift amount out of range") }
+        let shifted = if left { value << amount } else { value >> amount };
+        self.stack.push(Int(shifted));
+    }
+
+    /// `and`, `or` and `xor` on bools.
+    pub fn logic(&mut self, native: Nat) {
+        let rhs = self.pop_bool();
+        let lhs = self.pop_bool();
+        self.stack.push(Bool(match native {
+            Nat::And => lhs && rhs,
+            Nat::Or => lhs || rhs,
+            _ => lhs != rhs,
+        }));
+    }
+
+    pub fn int_to_float(&mut self) {
+        let value = self.pop_int();
+        self.stack.push(Number(value as f64));
+    }
+
+    /// Truncates toward zero and saturates at the int range; NaN becomes 0.
+    pub fn float_to_int(&mut self) {
+        let value = self.pop_num();
+        self.stack.push(Int(value as i64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::Token::*;
+    use super::VirtualMachine;
+
+    /// Run `source` and return the ints it leaves on the stack.
+    fn ints(source: &str) -> Vec<i64> {
+        let mut vm = VirtualMachine::new();
+        vm.include("test".to_string(), source.to_string());
+        vm.interpret();
+        return vm.stack.iter().map(|token| match token {
+            Int(value) => *value,
+            _ => panic!("expected only ints on the stack"),
+        }).collect();
+    }
+
+    /// Run `source` inside `catch` and return the error it raised.
+    fn error(source: &str) -> String {
+        let mut vm = VirtualMachine::new();
+        vm.include("test".to_string(), format!("{{ {} }} catch", source));
+        vm.interpret();
+        let Some(Str(i)) = vm.stack.last() else { panic!("nothing was raised") };
+        return vm.strs[*i].clone();
+    }
+
+    #[test]
+    fn arithmetic_wraps() {
+        assert_eq!(ints("0x7fffffffffffffff 1i +"), [i64::MIN]);
+        assert_eq!(ints("0x8000000000000000 1i -"), [i64::MAX]);
+        assert_eq!(ints("0x100000000 dup *"), [0]);
+        assert_eq!(ints("0x7fffffffffffffff 2i *"), [-2]);
+        assert_eq!(ints("0x8000000000000000 neg"), [i64::MIN]);
+        assert_eq!(ints("0x8000000000000000 abs"), [i64::MIN]);
+    }
+
+    #[test]
+    fn division() {
+        assert_eq!(ints("-7i 2i /"), [-3]);
+        assert_eq!(ints("0x8000000000000000 -1i /"), [i64::MIN]);
+        assert_eq!(ints("0x8000000000000000 -1i mod"), [0]);
+        assert_eq!(error("1i 0i /"), "division by zero");
+        assert_eq!(error("1i 0i mod"), "division by zero");
+    }
+
+    #[test]
+    fn mod_is_never_negative() {
+        assert_eq!(ints("7i 2i mod"), [1]);
+        assert_eq!(ints("-7i 2i mod"), [1]);
+        assert_eq!(ints("7i -2i mod"), [1]);
+        assert_eq!(ints("-7i -2i mod"), [1]);
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!(ints("5i 0i shl"), [5]);
+        assert_eq!(ints("5i 0i shr"), [5]);
+        assert_eq!(ints("1i 63i shl"), [i64::MIN]);
+        assert_eq!(ints("3i 63i shl"), [i64::MIN]);
+        assert_eq!(ints("0x8000000000000000 63i shr"), [-1]);
+        assert_eq!(ints("0x7fffffffffffffff 63i shr"), [0]);
+        assert_eq!(error("1i 64i shl"), "shift amount out of range");
+        assert_eq!(error("1i 64i shr"), "shift amount out of range");
+        assert_eq!(error("1i -1i shl"), "shift amount out of range");
+    }
+
+    #[test]
+    fn hex_literals_fill_64_bits() {
+        assert_eq!(ints("0xffffffffffffffff"), [-1]);
+        assert_eq!(ints("0x7fffffffffffffff"), [i64::MAX]);
+        assert_eq!(ints("-0xff"), [-255]);
+        assert_eq!(ints("0x8000000000000000"), [i64::MIN]);
+    }
+
+    #[test]
+    fn ints_index_and_count() {
+        assert_eq!(ints("3i allot-ints dup 7i 1i rot set 1i swap get"), [7]);
+        assert_eq!(ints("2i { 1i } times"), [1, 1]);
+        assert_eq!(ints("4i 5i 2i pick"), [4, 5, 4]);
+    }
+
+    #[test]
+    fn float_to_int_saturates() {
+        assert_eq!(ints("0 0 / float>int"), [0]);
+        assert_eq!(ints("1e300 float>int"), [i64::MAX]);
+        assert_eq!(ints("-1e300 float>int"), [i64::MIN]);
+        assert_eq!(ints("1 0 / float>int"), [i64::MAX]);
+        assert_eq!(ints("-2.9 float>int"), [-2]);
+    }
+}
@@ -0,0 +1,95 @@
+use crate::model::{Token, Token::*};
+use super::VirtualMachine;
+
+/// Locals bound by one call. `depth` is the control stack height at the
+/// time of binding, when the call's return address is on top. `body` is the
+/// start of the definition that declared them.
+#[derive(Clone, Copy)]
+pub struct Frame {
+    pub base: usize,
+    pub depth: usize,
+    pub body: usize,
+}
+
+impl VirtualMachine {
+    pub fn bind_locals(&mut self, count: usize, body: usize) {
+        if self.stack.len() < count { self.panic("stack is empty") }
+        let values = self.stack.split_off(self.stack.len() - count);
+        self.frames.push(Frame { base: self.locals.len(), depth: self.ctrl.len(), body });
+        self.locals.extend(values);
+    }
+
+    pub fn read_local(&self, slot: usize, body: usize) -> Token {
+        let i = self.local_index(slot, body);
+        return self.locals[i];
+    }
+
+    pub fn write_local(&mut self, slot: usize, body: usize, token: Token) {
+        let i = self.local_index(slot, body);
+        self.locals[i] = token;
+    }
+
+    /// Position of `slot` in the latest frame of `body`. Quotations inside
+    /// the body may run while other words have bound locals on top of it.
+    fn local_index(&self, slot: usize, body: usize) -> usize {
+        let Some(frame) = self.frames.iter().rev().find(|e| e.body == body) else {
+            self.panic("local is not bound")
+        };
+        return frame.base + slot;
+    }
+
+    /// Whether the innermost locals belong to the body currently executing.
+    pub fn owns_locals(&self) -> bool {
+        return self.frames.last().is_some_and(|e| e.depth >= self.ctrl.len());
+    }
+
+    /// Release the locals of the body that is about to return.
+    pub fn release_locals(&mut self) {
+        while self.owns_locals() {
+            let frame = self.frames.pop().unwrap();
+            self.locals.truncate(frame.base);
+        }
+    }
+
+    pub fn next_local(&self) -> (usize, usize) {
+        if let Some(Local(slot, body)) = self.tokens.get(self.index + 1) { return (*slot, *body) }
+        self.panic("-> requires a local");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::Token::*;
+    use super::VirtualMachine;
+
+    /// Run `source` and return the numbers it leaves on the stack.
+    fn numbers(source: &str) -> Vec<f64> {
+        let mut vm = VirtualMachine::new();
+        vm.include("test".to_string(), source.to_string());
+        vm.interpret();
+        return vm.stack.iter().map(|token| match token {
+            Number(value) => *value,
+            _ => panic!("expected only numbers on the stack"),
+        }).collect();
+    }
+
+    #[test]
+    fn quotation_reads_the_locals_of_its_definition() {
+        let source = "def apply2 ( q ) { {: q :} q invoke q invoke } ; \
+                      def f ( a ) { {: a :} { a } apply2 } ; 7 f";
+        assert_eq!(numbers(source), [7., 7.]);
+    }
+
+    #[test]
+    fn quotation_writes_the_locals_of_its_definition() {
+        let source = "def apply2 ( q ) { {: q :} q invoke q invoke } ; \
+                      def g ( a ) { {: a :} { a 1 + -> a } apply2 a } ; 5 g";
+        assert_eq!(numbers(source), [7.]);
+    }
+
+    #[test]
+    fn recursion_binds_fresh_locals() {
+        let source = "def down ( n - ) { {: n :} n n 0 > { n 1 - recurse } if n } ; 2 down";
+        assert_eq!(numbers(source), [2., 1., 0., 0., 1., 2.]);
+    }
+}
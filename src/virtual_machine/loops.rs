@@ -0,0 +1,83 @@
+use crate::model::{LoopFrame, Token::*};
+use super::VirtualMachine;
+use super::combinators::NATIVE_RETURN;
+
+impl VirtualMachine {
+    /// Drive `{ cond } { body } while`: run cond, then body while it holds.
+    pub fn step_while(&mut self) -> usize {
+        let top = self.loops.len().wrapping_sub(1);
+        if let Some(LoopFrame::While { cond, body, at, depth, testing, .. }) = self.loops.last_mut() {
+            if *at == self.index && *depth == self.ctrl.len() {
+                let (cond, body, run_body) = (*cond, *body, *testing);
+                if run_body && !self.pop_bool() {
+                    self.loops.pop();
+                    return self.index + 1;
+                }
+                if let Some(LoopFrame::While { testing, .. }) = self.loops.get_mut(top) {
+                    *testing = !run_body;
+                }
+                self.ctrl.push(Jump(self.index));
+                return if run_body { body } else { cond };
+            }
+        }
+        let body = self.pop_jump();
+        let cond = self.pop_jump();
+        let (at, depth, cases) = (self.index, self.ctrl.len(), self.cases.len());
+        self.loops.push(LoopFrame::While { cond, body, at, depth, cases, testing: true });
+        self.ctrl.push(Jump(self.index));
+        return cond;
+    }
+
+    /// Drive `{ body } until`: run body until it leaves true.
+    pub fn step_until(&mut self) -> usize {
+        if let Some(LoopFrame::Until { body, at, depth, .. }) = self.loops.last() {
+            if *at == self.index && *depth == self.ctrl.len() {
+                let body = *body;
+                if self.pop_bool() {
+                    self.loops.pop();
+                    return self.index + 1;
+                }
+                self.ctrl.push(Jump(self.index));
+                return body;
+            }
+        }
+        let body = self.pop_jump();
+        let (at, depth, cases) = (self.index, self.ctrl.len(), self.cases.len());
+        self.loops.push(LoopFrame::Until { body, at, depth, cases });
+        self.ctrl.push(Jump(self.index));
+        return body;
+    }
+
+    /// Exit the innermost loop from anywhere in its body, also returning
+    /// from the quotations and words called since, and continue after the
+    /// token that drives the loop.
+    pub fn leave(&mut self) -> usize {
+        let Some(frame) = self.loops.last() else { self.panic("leave outside of a loop") };
+        let (depth, cases) = (frame.depth(), frame.cases());
+        let driver = match self.ctrl.get(depth) {
+            Some(Jump(driver)) if !self.ctrl[depth..].contains(&Jump(NATIVE_RETURN)) => *driver,
+            _ => self.panic("leave outside of a loop body"),
+        };
+        self.loops.pop();
+        self.ctrl.truncate(depth);
+        self.cases.truncate(cases);
+        while self.frames.last().is_some_and(|e| e.depth > depth) {
+            let frame = self.frames.pop().unwrap();
+            self.locals.truncate(frame.base);
+        }
+        return driver + 1;
+    }
+
+    /// Index of a counted loop, `outer` levels out from the innermost.
+    pub fn loop_index(&self, outer: usize) -> f64 {
+        let mut counted = self.loops.iter().rev().filter_map(|frame| match frame {
+            LoopFrame::Counted { from, step, count, .. } => {
+                Some(from + (*count as f64 - 1.) * step)
+            }
+            _ => None,
+        });
+        if let Some(index) = counted.nth(outer) { return index }
+        self.panic(["i outside of a counted loop", "j outside of two counted loops",
+            "k outside of three counted loops"][outer.min(2)]);
+    }
+}
@@ -0,0 +1,140 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use crate::model::{Token, Token::*};
+use super::arrays::ArrayData;
+use super::VirtualMachine;
+
+/// Map key. Strings are keyed by content, so equal strings from different
+/// allocations find the same entry. Floats sort before ints and ints
+/// before strings; `1` and `1i` are different keys.
+#[derive(Clone)]
+pub enum Key {
+    Num(f64),
+    Int(i64),
+    Str(String),
+}
+
+impl Key {
+    fn rank(&self) -> u8 {
+        return match self {
+            Key::Num(_) => 0,
+            Key::Int(_) => 1,
+            Key::Str(_) => 2,
+        };
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return match (self, other) {
+            (Key::Num(a), Key::Num(b)) => a.total_cmp(b),
+            (Key::Int(a), Key::Int(b)) => a.cmp(b),
+            (Key::Str(a), Key::Str(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        };
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for Key {}
+
+pub type MapData = BTreeMap<Key, Token>;
+
+impl VirtualMachine {
+    pub fn alloc_map(&mut self, map: MapData) -> Token {
+        self.allocations += 1;
+        if let Some(i) = self.free_maps.pop() {
+            self.maps[i] = map;
+            return Map(i);
+        }
+        self.maps.push(map);
+        return Map(self.maps.len() - 1);
+    }
+
+    fn pop_key(&mut self) -> Key {
+        return match self.pop_token() {
+            // -0 and 0 are the same key
+            Number(x) => Key::Num(if x == 0. { 0. } else { x }),
+            Int(x) => Key::Int(x),
+            Str(i) => Key::Str(self.strs[i].clone()),
+            token => self.stop("expected a string or number key", Some(token)),
+        };
+    }
+
+    fn key_token(&mut self, key: Key) -> Token {
+        return match key {
+            Key::Num(x) => Number(x),
+            Key::Int(x) => Int(x),
+            Key::Str(s) => self.alloc_str(s),
+        };
+    }
+
+    pub fn map_set(&mut self) {
+        let map_ref = self.pop_map();
+        let key = self.pop_key();
+        let value = self.pop_token();
+        self.maps[map_ref].insert(key, value);
+    }
+
+    pub fn map_get(&mut self) {
+        let map_ref = self.pop_map();
+        let key = self.pop_key();
+        let Some(value) = self.maps[map_ref].get(&key) else {
+            self.panic("key not in map")
+        };
+        self.stack.push(*value);
+    }
+
+    pub fn map_has(&mut self) {
+        let map_ref = self.pop_map();
+        let key = self.pop_key();
+        self.stack.push(Bool(self.maps[map_ref].contains_key(&key)));
+    }
+
+    pub fn map_delete(&mut self) {
+        let map_ref = self.pop_map();
+        let key = self.pop_key();
+        self.maps[map_ref].remove(&key);
+    }
+
+    /// Cell array of the keys in order.
+    pub fn map_keys(&mut self) {
+        let map_ref = self.pop_map();
+        let keys: Vec<Key> = self.maps[map_ref].keys().cloned().collect();
+        let cells = keys.into_iter().map(|key| self.key_token(key)).collect();
+        let array = self.alloc_array(ArrayData::Cells(cells));
+        self.stack.push(array);
+    }
+
+    /// Call the quotation with key and value of every entry, in key order.
+    /// Entries are copied first, so the quotation may change the map.
+    pub fn map_each(&mut self) {
+        let callable = self.pop_callable();
+        let map_ref = self.pop_map();
+        let entries: Vec<(Key, Token)> =
+            self.maps[map_ref].iter().map(|(k, v)| (k.clone(), *v)).collect();
+        // the quotation may delete entries whose values are still to come
+        let base = self.roots.len();
+        self.roots.push(Map(map_ref));
+        self.roots.extend(entries.iter().map(|e| e.1));
+        for (key, value) in entries {
+            let key = self.key_token(key);
+            self.stack.push(key);
+            self.stack.push(value);
+            self.run_callable(callable);
+        }
+        self.roots.truncate(base);
+    }
+}
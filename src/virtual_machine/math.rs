@@ -0,0 +1,37 @@
+use crate::model::Token::*;
+use super::VirtualMachine;
+
+impl VirtualMachine {
+    pub fn unary(&mut self, f: fn(f64) -> f64) {
+        let value = self.pop_num();
+        self.stack.push(Number(f(value)));
+    }
+
+    pub fn binary(&mut self, f: fn(f64, f64) -> f64) {
+        let rhs = self.pop_num();
+        let lhs = self.pop_num();
+        self.stack.push(Number(f(lhs, rhs)));
+    }
+
+    /// ( x lo hi - x ) limited to lo..=hi.
+    pub fn clamp(&mut self) {
+        let hi = self.pop_num();
+        let lo = self.pop_num();
+        let value = self.pop_num();
+        if lo > hi { self.panic("clamp bounds are reversed") }
+        self.stack.push(Number(value.max(lo).min(hi)));
+    }
+
+    /// ( a b t - x ) from a at t = 0 to b at t = 1.
+    pub fn lerp(&mut self) {
+        let t = self.pop_num();
+        let b = self.pop_num();
+        let a = self.pop_num();
+        self.stack.push(Number(a + (b - a) * t));
+    }
+
+    pub fn classify(&mut self, f: fn(f64) -> bool) {
+        let value = self.pop_num();
+        self.stack.push(Bool(f(value)));
+    }
+}
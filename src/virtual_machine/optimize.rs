@@ -0,0 +1,108 @@
+use crate::model::{Nat, Token, Token::*};
+use super::VirtualMachine;
+
+impl VirtualMachine {
+    /// Rewrite the body of the word starting at `start` in place. Removed
+    /// tokens become `Empty` so every jump target keeps its position, and
+    /// the replaced tokens are remembered for the debugger.
+    pub fn optimize_word(&mut self, start: usize) {
+        let end = self.body_end(start);
+        for i in start..end {
+            if i > 0 && self.tokens[i - 1] == Native(Nat::Quote) { continue }
+            if let Jump(target) = self.tokens[i] {
+                if let Some(value) = self.constant_word(target) {
+                    self.rewrite(i, Number(value));
+                }
+            }
+        }
+        while self.peephole(start, end) {}
+    }
+
+    pub fn source_token(&self, index: usize) -> &Token {
+        return self.originals.get(&index).unwrap_or(&self.tokens[index]);
+    }
+
+    pub fn body_end(&self, start: usize) -> usize {
+        let mut depth = 0;
+        for i in start..self.tokens.len() {
+            match self.tokens[i] {
+                Native(Nat::OpenBrace) => depth += 1,
+                Native(Nat::CloseBrace) if depth == 0 => return i,
+                Native(Nat::CloseBrace) => depth -= 1,
+                _ => {}
+            }
+        }
+        return self.tokens.len();
+    }
+
+    fn constant_word(&self, start: usize) -> Option<f64> {
+        if let (Some(Number(value)), Some(Native(Nat::CloseBrace))) =
+            (self.tokens.get(start), self.tokens.get(start + 1)) {
+            return Some(*value);
+        }
+        return None;
+    }
+
+    fn rewrite(&mut self, index: usize, token: Token) {
+        if !self.originals.contains_key(&index) {
+            self.originals.insert(index, self.tokens[index]);
+        }
+        self.tokens[index] = token;
+    }
+
+    fn peephole(&mut self, start: usize, end: usize) -> bool {
+        let live: Vec<usize> =
+            (start..end).filter(|i| self.tokens[*i] != Empty).collect();
+        for n in 0..live.len() {
+            if n > 0 && self.tokens[live[n - 1]] == Native(Nat::Quote) { continue }
+            let window: Vec<Token> =
+                live[n..].iter().take(3).map(|i| self.tokens[*i]).collect();
+            if let Some((len, replacement)) = fold(&window) {
+                for k in &live[n..n + len] { self.rewrite(*k, Empty) }
+                if let Some(token) = replacement {
+                    self.rewrite(live[n + len - 1], token);
+                }
+                return true;
+            }
+        }
+        return false;
+    }
+}
+
+/// Match the start of `window` against the known patterns, returning how
+/// many tokens it covers and what should remain in their place.
+fn fold(window: &[Token]) -> Option<(usize, Option<Token>)> {
+    use Nat::*;
+    return match window {
+        [Native(Swap), Native(Swap), ..] => Some((2, None)),
+        [Native(Dup), Native(Drop), ..] => Some((2, None)),
+        [Number(_), Native(Drop), ..] => Some((2, None)),
+        [Native(Rot), Native(Rot), Native(Rot)] => Some((3, None)),
+        [Number(x), Native(Plus | Minus), ..] if *x == 0. => Some((2, None)),
+        [Number(x), Native(Multiply | Divide), ..] if *x == 1. => Some((2, None)),
+        [Number(a), Number(b), Native(op)] => {
+            let value = match op {
+                Plus => a + b,
+                Minus => a - b,
+                Multiply => a * b,
+                Divide => a / b,
+                Pow => a.powf(*b),
+                Mod => a.rem_euclid(*b),
+                _ => return None,
+            };
+            Some((3, Some(Number(value))))
+        }
+        [Number(x), Native(op), ..] => {
+            let value = match op {
+                Floor => x.floor(),
+                Ceil => x.ceil(),
+                Round => x.round(),
+                Abs => x.abs(),
+                Neg => -x,
+                _ => return None,
+            };
+            Some((2, Some(Number(value))))
+        }
+        _ => None,
+    };
+}
@@ -14,6 +14,14 @@ impl VirtualMachine {
         self.stop("expected a number", token);
     }
 
+    /// A whole number used as an index or count, given as a float or int.
+    pub fn pop_index(&mut self) -> f64 {
+        let token = self.stack.pop();
+        if let Some(Token::Number(value)) = token { return value }
+        if let Some(Token::Int(value)) = token { return value as f64 }
+        self.stop("expected a number or int", token);
+    }
+
     pub fn pop_bool(&mut self) -> bool {
         let token = self.stack.pop();
         if let Some(Token::Bool(value)) = token { return value }
@@ -32,6 +40,12 @@ impl VirtualMachine {
         self.stop("expected a jump", token);
     }
 
+    pub fn pop_callable(&mut self) -> Token {
+        let token = self.stack.pop();
+        if let Some(Token::Jump(_) | Token::Closure(_)) = token { return token.unwrap() }
+        self.stop("expected a jump or closure", token);
+    }
+
     pub fn pop_var(&mut self) -> usize {
         let token = self.stack.pop();
         if let Some(Token::Var(value)) = token { return value }
@@ -40,11 +54,30 @@ impl VirtualMachine {
 
     pub fn pop_array(&mut self) -> usize {
         let token = self.stack.pop();
-        if let Some(Token::Array(value)) = token { return value }
+        if let Some(Token::Array(value)) = token {
+            if value < self.arrays.len() { return value }
+            self.stop("array handle is out of range", token);
+        }
         self.stop("expected an array", token);
     }
 
-    fn stop(&self, msg: &'static str, token: Option<Token>) -> ! {
+    pub fn pop_map(&mut self) -> usize {
+        let token = self.stack.pop();
+        if let Some(Token::Map(value)) = token {
+            if value < self.maps.len() { return value }
+            self.stop("map handle is out of range", token);
+        }
+        self.stop("expected a map", token);
+    }
+
+    pub fn pop_ctrl(&mut self) -> Token {
+        let token = self.ctrl.pop();
+        if let Some(token) = token { return token }
+        self.stop("control stack is empty", token);
+    }
+
+    pub fn stop(&self, msg: &'static str, token: Option<Token>) -> ! {
+        self.raise(msg);
         let word = self.tokens[self.index];
         let word_desc = self.serialize_token(&word);
         let tok = if let Some(val) = token { val.to_string() } else { "-".to_string() };
@@ -0,0 +1,57 @@
+use crate::model::Token::*;
+use super::VirtualMachine;
+
+/// Seed used until `seed` or `--seed` picks another, so runs repeat.
+pub const DEFAULT_SEED: u64 = 0x5eed;
+
+impl VirtualMachine {
+    pub fn seed_random(&mut self, seed: u64) {
+        self.rng = seed;
+    }
+
+    /// Next value of the SplitMix64 generator.
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        return z ^ (z >> 31);
+    }
+
+    /// Uniform in 0..bound without modulo bias.
+    fn random_below(&mut self, bound: u64) -> u64 {
+        return ((self.next_random() as u128 * bound as u128) >> 64) as u64;
+    }
+
+    pub fn seed(&mut self) {
+        let seed = match self.stack.last() {
+            Some(Int(_)) => self.pop_int(),
+            _ => self.pop_num() as i64,
+        };
+        self.seed_random(seed as u64);
+    }
+
+    /// Float in [0, 1).
+    pub fn random(&mut self) {
+        let value = (self.next_random() >> 11) as f64 / (1u64 << 53) as f64;
+        self.stack.push(Number(value));
+    }
+
+    /// ( n - k ) with k in 0..n, an int if n is.
+    pub fn random_int(&mut self) {
+        let int = matches!(self.stack.last(), Some(Int(_)));
+        let bound = if int { self.pop_int() } else { self.pop_num().floor() as i64 };
+        if bound <= 0 { self.panic("random-int needs a positive bound") }
+        let value = self.random_below(bound as u64);
+        self.stack.push(if int { Int(value as i64) } else { Number(value as f64) });
+    }
+
+    /// Shuffle an array in place.
+    pub fn shuffle(&mut self) {
+        let array_ref = self.pop_array();
+        for i in (1..self.arrays[array_ref].len()).rev() {
+            let j = self.random_below(i as u64 + 1) as usize;
+            self.arrays[array_ref].swap(i, j);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use crate::model::Token::*;
+use super::VirtualMachine;
+
+impl VirtualMachine {
+    fn pop_string(&mut self) -> String {
+        let str_i = self.pop_str();
+        return self.strs[str_i].clone();
+    }
+
+    /// Join two strings, or two arrays with `concat_arrays`.
+    pub fn concat(&mut self) {
+        if let Some(Array(_)) = self.stack.last() {
+            let right = self.pop_array();
+            let left = self.pop_array();
+            return self.concat_arrays(left, right);
+        }
+        let right = self.pop_string();
+        let left = self.pop_string();
+        let token = self.alloc_str(left + &right);
+        self.stack.push(token);
+    }
+
+    /// Length of a string in characters, of an array in elements or of a
+    /// map in entries.
+    pub fn length(&mut self) {
+        let len = match self.pop_token() {
+            Str(i) => self.strs[i].chars().count(),
+            Array(i) if i < self.arrays.len() => self.arrays[i].len(),
+            Map(i) if i < self.maps.len() => self.maps[i].len(),
+            token => self.stop("expected a string or array", Some(token)),
+        };
+        self.stack.push(Number(len as f64));
+    }
+
+    pub fn substring(&mut self) {
+        let len = self.pop_index().max(0.) as usize;
+        let start = self.pop_index().max(0.) as usize;
+        let value = self.pop_string();
+        let sub: String = value.chars().skip(start).take(len).collect();
+        let token = self.alloc_str(sub);
+        self.stack.push(token);
+    }
+
+    /// Character index of the first occurrence of a needle, or -1.
+    pub fn index_of(&mut self) {
+        let needle = self.pop_string();
+        let value = self.pop_string();
+        let index = match value.find(&needle) {
+            Some(byte) => value[..byte].chars().count() as f64,
+            None => -1.,
+        };
+        self.stack.push(Number(index));
+    }
+
+    /// Push every part of the string between separators, then their count.
+    pub fn split(&mut self) {
+        let separator = self.pop_string();
+        let value = self.pop_string();
+        if separator.is_empty() { self.panic("split requires a separator") }
+        let parts: Vec<String> = value.split(&separator).map(|e| e.to_string()).collect();
+        let count = parts.len();
+        for part in parts {
+            let token = self.alloc_str(part);
+            self.stack.push(token);
+        }
+        self.stack.push(Number(count as f64));
+    }
+
+    pub fn upper_case(&mut self) {
+        let value = self.pop_string();
+        let token = self.alloc_str(value.to_uppercase());
+        self.stack.push(token);
+    }
+
+    pub fn lower_case(&mut self) {
+        let value = self.pop_string();
+        let token = self.alloc_str(value.to_lowercase());
+        self.stack.push(token);
+    }
+
+    pub fn compare_strings(&mut self, less: bool) {
+        let right = self.pop_string();
+        let left = self.pop_string();
+        self.stack.push(Bool(if less { left < right } else { left == right }));
+    }
+
+    pub fn number_to_string(&mut self) {
+        let value = match self.stack.last() {
+            Some(Int(_)) => self.pop_int().to_string(),
+            _ => self.pop_num().to_string(),
+        };
+        let token = self.alloc_str(value);
+        self.stack.push(token);
+    }
+
+    pub fn string_to_number(&mut self) {
+        let value = self.pop_string();
+        let Ok(number) = value.trim().parse::<f64>() else {
+            self.panic("string is not a number")
+        };
+        self.stack.push(Number(number));
+    }
+}
@@ -0,0 +1,82 @@
+use crate::model::{Nat, Token, Token::*};
+use super::arrays::ArrayData;
+use super::maps::Key;
+use super::VirtualMachine;
+
+/// Layout declared by `struct name field... end-struct`.
+pub struct StructDef {
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+impl VirtualMachine {
+    /// Emit the words of the struct declared by `struct_names`: the
+    /// constructor `name ( fields - ref )` and `name.field@`, `name.field!`
+    /// for every field.
+    pub fn define_struct(&mut self) {
+        let names = std::mem::take(&mut self.struct_names);
+        let Some((name, fields)) = names.split_first() else {
+            self.panic("struct needs a name")
+        };
+        self.structs.push(StructDef { name: name.clone(), fields: fields.to_vec() });
+        let id = self.structs.len() - 1;
+        self.tokens.push(Empty);
+        self.emit_word(name, &[Number(id as f64), Native(Nat::NewStruct)]);
+        for (i, field) in fields.iter().enumerate() {
+            let at = Number(i as f64);
+            let read = [at, Native(Nat::Swap), Native(Nat::Get)];
+            let write = [at, Native(Nat::Swap), Native(Nat::Set)];
+            self.emit_word(&format!("{}.{}@", name, field), &read);
+            self.emit_word(&format!("{}.{}!", name, field), &write);
+        }
+    }
+
+    fn emit_word(&mut self, name: &str, body: &[Token]) {
+        let symbol = self.add_symbol(name);
+        self.tokens.push(Symbol(symbol));
+        self.tokens.push(Native(Nat::OpenBrace));
+        self.tokens.extend_from_slice(body);
+        self.tokens.push(Native(Nat::CloseBrace));
+        self.tokens.push(Native(Nat::Semicolon));
+    }
+
+    /// Allocate an instance of struct `id`, taking its fields from the stack.
+    pub fn new_struct(&mut self) {
+        let id = self.pop_num() as usize;
+        let Some(def) = self.structs.get(id) else {
+            self.panic("unknown struct")
+        };
+        let count = def.fields.len();
+        let mut fields = vec![Number(0.); count];
+        for i in (0..count).rev() { fields[i] = self.pop_token() }
+        let token = self.alloc_array(ArrayData::Cells(fields));
+        if let Array(array_ref) = token { self.records.insert(array_ref, id); }
+        self.stack.push(token);
+    }
+
+    /// Elements of the array behind `token`, labelled with field names when
+    /// it is a struct instance, or the entries of a map.
+    pub fn inspect(&self, token: &Token) -> Vec<String> {
+        if let Map(map_ref) = token {
+            let Some(map) = self.maps.get(*map_ref) else { return Vec::new() };
+            return map.iter().map(|(key, value)| {
+                let key = match key {
+                    Key::Num(x) => x.to_string(),
+                    Key::Int(x) => format!("{}i", x),
+                    Key::Str(s) => format!("\"{}\"", s),
+                };
+                format!("{}: {}", key, self.serialize_token(value))
+            }).collect();
+        }
+        let Array(array_ref) = token else { return Vec::new() };
+        let Some(array) = self.arrays.get(*array_ref) else { return Vec::new() };
+        let def = self.records.get(array_ref).map(|id| &self.structs[*id]);
+        return (0..array.len()).map(|i| {
+            let value = self.serialize_token(&array.get(i));
+            match def.and_then(|def| def.fields.get(i)) {
+                Some(field) => format!("{}: {}", field, value),
+                None => format!("{}: {}", i, value),
+            }
+        }).collect();
+    }
+}
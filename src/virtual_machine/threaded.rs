@@ -0,0 +1,252 @@
+use crate::model::{Nat, Token, Token::*};
+use super::VirtualMachine;
+
+const INLINE_LIMIT: usize = 8;
+
+/// Instruction of the threaded engine. Calls address `code` directly and
+/// common token pairs are fused into a single instruction.
+#[derive(Clone, Copy)]
+pub enum Op {
+    Push(Token),
+    Native(Nat),
+    Call(usize),
+    Return,
+    LitAdd(f64),
+    LitSub(f64),
+    LitMul(f64),
+    LitDiv(f64),
+    LitPick(usize),
+    DupMul,
+    VarRead(usize),
+    VarWrite(usize),
+}
+
+impl VirtualMachine {
+    /// Compile every defined word the threaded engine can handle, in
+    /// definition order so callees are compiled before their callers.
+    pub fn compile_words(&mut self) {
+        let jumps: Vec<usize> = self.dict.iter().map(|e| e.jump).collect();
+        for jump in jumps { self.compile_word(jump) }
+    }
+
+    /// Compile the word starting at `start` if its body is straight-line
+    /// code that only calls other compiled words. Anything else is left to
+    /// the token interpreter.
+    pub fn compile_word(&mut self, start: usize) {
+        let end = self.body_end(start);
+        let mut ops: Vec<(Op, usize)> = Vec::new();
+        let mut ctrl_depth = 0;
+        for i in start..end {
+            let op = match self.tokens[i] {
+                Empty => continue,
+                Jump(target) => match self.compiled.get(&target) {
+                    Some(entry) => match self.inlineable(*entry) {
+                        Some(body) => {
+                            ops.extend(body.iter().map(|op| (*op, i)));
+                            continue;
+                        }
+                        None => Op::Call(*entry),
+                    },
+                    None => return,
+                },
+                Native(Nat::StoreCtrl) => { ctrl_depth += 1; Op::Native(Nat::StoreCtrl) }
+                Native(Nat::ReadCtrl) if ctrl_depth > 0 => {
+                    ctrl_depth -= 1;
+                    Op::Native(Nat::ReadCtrl)
+                }
+                Native(Nat::CopyCtrl) if ctrl_depth > 0 => Op::Native(Nat::CopyCtrl),
+                Native(nat) if is_straight(nat) => Op::Native(nat),
+                Native(_) | Control(_) | Local(..) => return,
+                token => Op::Push(token),
+            };
+            ops.push((op, i));
+        }
+        if ctrl_depth != 0 { return }
+        ops.push((Op::Return, end));
+        self.compiled.insert(start, self.code.len());
+        for (op, src) in fuse(ops) {
+            self.code.push(op);
+            self.code_src.push(src);
+        }
+    }
+
+    /// Small callees without calls of their own are copied into the caller.
+    fn inlineable(&self, entry: usize) -> Option<Vec<Op>> {
+        let len = self.code[entry..].iter().position(|op| matches!(op, Op::Return))?;
+        let body = &self.code[entry..entry + len];
+        if len > INLINE_LIMIT || body.iter().any(|op| matches!(op, Op::Call(_))) {
+            return None;
+        }
+        return Some(body.to_vec());
+    }
+
+    pub fn run_compiled(&mut self, entry: usize) {
+        let mut pc = entry;
+        let base = self.code_returns.len();
+        loop {
+            self.index = self.code_src[pc];
+            match self.code[pc] {
+                Op::Push(token) => self.stack.push(token),
+                Op::Native(Nat::Drop) => { self.stack.pop(); }
+                Op::Native(Nat::Dup) => {
+                    let a = self.pop_token();
+                    self.stack.push(a);
+                    self.stack.push(a);
+                }
+                // short stacks are popped to report the underflow like the natives do
+                Op::Native(Nat::Swap) => {
+                    let len = self.stack.len();
+                    if len < 2 { self.pop_token(); self.pop_token(); }
+                    self.stack.swap(len - 1, len - 2);
+                }
+                Op::Native(Nat::Over) => {
+                    let len = self.stack.len();
+                    if len < 2 { self.pop_token(); self.pop_token(); }
+                    self.stack.push(self.stack[len - 2]);
+                }
+                Op::Native(Nat::Rot) => {
+                    let len = self.stack.len();
+                    if len < 3 { self.pop_token(); self.pop_token(); self.pop_token(); }
+                    self.stack[len - 3..].rotate_left(1);
+                }
+                // ints take the general path
+                Op::Native(nat) if matches!(self.stack.last(), Some(Int(_))) => {
+                    self.evaluate_native(nat);
+                }
+                Op::Native(Nat::Plus) => {
+                    let sum = self.pop_num() + self.pop_num();
+                    self.stack.push(Number(sum));
+                }
+                Op::Native(Nat::Minus) => {
+                    let rhs = self.pop_num();
+                    let lhs = self.pop_num();
+                    self.stack.push(Number(lhs - rhs));
+                }
+                Op::Native(Nat::Multiply) => {
+                    let prod = self.pop_num() * self.pop_num();
+                    self.stack.push(Number(prod));
+                }
+                Op::Native(Nat::Neg) => {
+                    let value = self.pop_num();
+                    self.stack.push(Number(-value));
+                }
+                Op::Native(Nat::StoreCtrl) => {
+                    let token = self.pop_token();
+                    self.ctrl.push(token);
+                }
+                Op::Native(Nat::ReadCtrl) => {
+                    let token = self.ctrl.pop().unwrap();
+                    self.stack.push(token);
+                }
+                Op::Native(nat) => { self.evaluate_native(nat); }
+                Op::Call(target) => {
+                    self.code_returns.push(pc + 1);
+                    pc = target;
+                    continue;
+                }
+                Op::Return => {
+                    if self.code_returns.len() == base { return }
+                    pc = self.code_returns.pop().unwrap();
+                    continue;
+                }
+                // fused pairs run unfused on ints, to get the int or error path
+                Op::LitAdd(x) | Op::LitSub(x) | Op::LitMul(x) | Op::LitDiv(x)
+                    if matches!(self.stack.last(), Some(Int(_))) => {
+                    self.stack.push(Number(x));
+                    self.evaluate_native(lit_native(self.code[pc]));
+                }
+                Op::DupMul if matches!(self.stack.last(), Some(Int(_))) => {
+                    self.stack.push(self.stack[self.stack.len() - 1]);
+                    self.evaluate_native(Nat::Multiply);
+                }
+                Op::LitAdd(x) => {
+                    let value = self.pop_num();
+                    self.stack.push(Number(value + x));
+                }
+                Op::LitSub(x) => {
+                    let value = self.pop_num();
+                    self.stack.push(Number(value - x));
+                }
+                Op::LitMul(x) => {
+                    let value = self.pop_num();
+                    self.stack.push(Number(value * x));
+                }
+                Op::LitDiv(x) => {
+                    let value = self.pop_num();
+                    self.stack.push(Number(value / x));
+                }
+                Op::LitPick(offset) => {
+                    if offset == 0 || offset > self.stack.len() {
+                        self.panic("stack is empty");
+                    }
+                    self.stack.push(self.stack[self.stack.len() - offset]);
+                }
+                Op::DupMul => {
+                    let value = self.pop_num();
+                    self.stack.push(Number(value * value));
+                }
+                Op::VarRead(var) => self.stack.push(self.vars[var]),
+                Op::VarWrite(var) => {
+                    let token = self.pop_token();
+                    self.vars[var] = token;
+                }
+            }
+            pc += 1;
+        }
+    }
+}
+
+/// Natives that neither change control flow nor depend on the token stream.
+fn is_straight(nat: Nat) -> bool {
+    use Nat::*;
+    return matches!(nat,
+        Plus | Minus | Multiply | Divide | Pow | Mod | Floor | Ceil | Round |
+        Abs | Neg | Equal | GreaterThan | LessThan | Not | True | False |
+        Swap | Rot | Pick | Over | Dup | Drop | Read | Write | Set | Get |
+        ArrayLength | Resize | Fill | AllotBytes | AllotInts | AllotCells |
+        NewStruct | MapNew | MapSet | MapGet | MapHas | MapDelete | MapKeys |
+        VecNew | Push | Pop | Insert | Remove | Slice | Sort |
+        And | Or | Xor | Shl | Shr | Invert | IntToFloat | FloatToInt |
+        Sqrt | Sin | Cos | Tan | Atan2 | Exp | Ln | Log10 | Min | Max | Clamp |
+        Lerp | Pi | E | IsNan | IsInf | Seed | Random | RandomInt | Shuffle |
+        ComplexAdd | ComplexMul | ComplexSq | ComplexAbs2 | Conj |
+        Questionmark | Allot | Dot | Dots | I | Assert | DisplayImage |
+        JumpAsNumber | StringAsNumber | VarAsNumber | ArrayAsNumber |
+        NumberAsJump | NumberAsString | NumberAsVar | NumberAsArray
+    );
+}
+
+/// The arithmetic native a literal op was fused from.
+fn lit_native(op: Op) -> Nat {
+    return match op {
+        Op::LitAdd(_) => Nat::Plus,
+        Op::LitSub(_) => Nat::Minus,
+        Op::LitMul(_) => Nat::Multiply,
+        _ => Nat::Divide,
+    };
+}
+
+fn fuse(ops: Vec<(Op, usize)>) -> Vec<(Op, usize)> {
+    let mut fused: Vec<(Op, usize)> = Vec::with_capacity(ops.len());
+    for (op, src) in ops {
+        let prev = fused.last().map(|e| e.0);
+        let pair = match (prev, op) {
+            (Some(Op::Push(Number(x))), Op::Native(Nat::Plus)) => Some(Op::LitAdd(x)),
+            (Some(Op::Push(Number(x))), Op::Native(Nat::Minus)) => Some(Op::LitSub(x)),
+            (Some(Op::Push(Number(x))), Op::Native(Nat::Multiply)) => Some(Op::LitMul(x)),
+            (Some(Op::Push(Number(x))), Op::Native(Nat::Divide)) => Some(Op::LitDiv(x)),
+            (Some(Op::Push(Number(x))), Op::Native(Nat::Pick)) if x >= 0. => {
+                Some(Op::LitPick(x as usize))
+            }
+            (Some(Op::Native(Nat::Dup)), Op::Native(Nat::Multiply)) => Some(Op::DupMul),
+            (Some(Op::Push(Var(var))), Op::Native(Nat::Read)) => Some(Op::VarRead(var)),
+            (Some(Op::Push(Var(var))), Op::Native(Nat::Write)) => Some(Op::VarWrite(var)),
+            _ => None,
+        };
+        match pair {
+            Some(pair) => { fused.pop(); fused.push((pair, src)) }
+            None => fused.push((op, src)),
+        }
+    }
+    return fused;
+}